@@ -1,15 +1,53 @@
-use crate::{parser::Parser, Code, Instruction};
+use crate::{parser::Parser, type_check, Code, Instruction, Version};
 
 #[inline]
 pub fn assemble(source: &str) -> Result<Code, String> {
-    let source = std::fmt::format(format_args!(
-        "(version {}.{}.{})\n{}",
-        env!("CARGO_PKG_VERSION_MAJOR"),
-        env!("CARGO_PKG_VERSION_MINOR"),
-        env!("CARGO_PKG_VERSION_PATCH"),
-        source
-    ));
-    Ok(Instruction::from_sexprs(
-        &Parser::new(source.as_str()).parse()?,
-    )?)
+    let source = format!("(version {})\n{}", Version::current(), source);
+    let code = Instruction::from_sexprs(&Parser::new(source.as_str()).parse()?)?;
+
+    // Functions with typed signatures are checked while assembling
+    let report = type_check(&code);
+
+    if !report.diagnostics.is_empty() {
+        let diagnostics: Vec<String> = report.diagnostics.iter().map(|d| d.to_string()).collect();
+        return Err(format!("Type errors:\n  {}", diagnostics.join("\n  ")));
+    }
+
+    Ok(code)
+}
+
+// Turn every (call) immediately followed by (return) into a (call.tail)
+pub fn optimize_tail_calls(code: &mut Code) {
+    for index in 0..code.len() {
+        let is_tail = matches!(code.get(index + 1), Some(Instruction::Return));
+
+        match &mut code[index] {
+            Instruction::Call {
+                module,
+                function,
+                param_count,
+            } if is_tail => {
+                code[index] = Instruction::TailCall {
+                    module: std::mem::take(module),
+                    function: std::mem::take(function),
+                    param_count: *param_count,
+                };
+            }
+            Instruction::Fn { code, .. }
+            | Instruction::Module { code, .. }
+            | Instruction::Loop { block: code } => optimize_tail_calls(code),
+            Instruction::Then {
+                then_block,
+                else_block,
+            } => {
+                optimize_tail_calls(then_block);
+                optimize_tail_calls(else_block);
+            }
+            Instruction::Switch { cases, default } => {
+                cases.iter_mut().for_each(optimize_tail_calls);
+                optimize_tail_calls(default);
+            }
+            _ => {}
+        }
+    }
 }
@@ -13,6 +13,12 @@ pub struct ModuleBuilder {
     pub functions: HashMap<String, Function>, // Functions in the module
 }
 
+impl Default for ModuleBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ModuleBuilder {
     pub fn new() -> ModuleBuilder {
         ModuleBuilder {
@@ -1,15 +1,23 @@
+use crate::Encoding;
+
 pub(crate) struct ByteReader<'a> {
-    source: &'a Vec<u8>,
+    source: &'a [u8],
     position: usize,
     saved_position: usize,
+    encoding: Encoding, // Encoding of u32 and i32 values
 }
 
 impl<'a> ByteReader<'a> {
-    pub fn new(source: &'a Vec<u8>) -> ByteReader<'a> {
+    pub fn new(source: &'a [u8]) -> ByteReader<'a> {
+        ByteReader::with_encoding(source, Encoding::Fixed)
+    }
+
+    pub fn with_encoding(source: &'a [u8], encoding: Encoding) -> ByteReader<'a> {
         ByteReader {
-            source: source,
+            source,
             position: 0,
             saved_position: 0,
+            encoding,
         }
     }
 
@@ -31,28 +39,71 @@ impl<'a> ByteReader<'a> {
         }
     }
 
-    pub fn read_bytes(&mut self, count: usize) -> Option<Vec<u8>> {
-        if self.position + count <= self.source.len() {
-            let bytes = self.source[self.position..self.position + count].to_vec();
-            self.position += count;
-            Some(bytes)
-        } else {
-            None
-        }
+    // Borrow the next bytes of the source, nested blocks are decoded in place
+    pub fn read_bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .source
+            .get(self.position..self.position.checked_add(count)?)?;
+        self.position += count;
+        Some(bytes)
+    }
+
+    pub fn read_u16(&mut self) -> Option<u16> {
+        let bytes = self.read_bytes(2)?;
+
+        Some(u16::from_le_bytes([bytes[1], bytes[0]]))
     }
 
     pub fn read_u32(&mut self) -> Option<u32> {
+        if self.encoding == Encoding::Compact {
+            return self.read_leb128(false).map(|value| value as u32);
+        }
+
         let bytes = self.read_bytes(4)?;
 
         Some(u32::from_le_bytes([bytes[3], bytes[2], bytes[1], bytes[0]]))
     }
 
     pub fn read_i32(&mut self) -> Option<i32> {
+        if self.encoding == Encoding::Compact {
+            return self.read_leb128(true).map(|value| value as i32);
+        }
+
         let bytes = self.read_bytes(4)?;
 
         Some(i32::from_le_bytes([bytes[3], bytes[2], bytes[1], bytes[0]]))
     }
 
+    // Read a LEB128 value of at most 32 bits, None when it is truncated or too long
+    fn read_leb128(&mut self, signed: bool) -> Option<u64> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+
+        loop {
+            if shift >= 35 {
+                return None;
+            }
+
+            let byte = self.read_byte()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                if signed && shift < 64 && byte & 0x40 != 0 {
+                    value |= u64::MAX << shift; // Sign extend
+                }
+
+                let fits = if signed {
+                    i32::try_from(value as i64).is_ok()
+                } else {
+                    value <= u32::MAX as u64
+                };
+
+                return fits.then_some(value);
+            }
+        }
+    }
+
     pub fn read_f32(&mut self) -> Option<f32> {
         let bytes = self.read_bytes(4)?;
 
@@ -75,7 +126,7 @@ impl<'a> ByteReader<'a> {
         let bytes = self.read_bytes(length)?;
 
         // Convert the bytes to a string
-        Some(String::from_utf8(bytes).unwrap())
+        std::str::from_utf8(bytes).ok().map(str::to_string)
     }
 }
 
@@ -90,7 +141,7 @@ mod tests {
 
         assert_eq!(reader.read_byte(), Some(0x01));
         assert_eq!(reader.read_byte(), Some(0x02));
-        assert_eq!(reader.read_bytes(2), Some(vec![0x03, 0x04]));
+        assert_eq!(reader.read_bytes(2), Some(&[0x03, 0x04][..]));
         assert_eq!(reader.read_byte(), None);
     }
 
@@ -126,6 +177,39 @@ mod tests {
         assert_eq!(reader.read_u32(), Some(0x89ABCDEF));
     }
 
+    #[test]
+    fn compact_integers_round_trip() {
+        let mut source = Vec::new();
+        let mut writer =
+            crate::byte_writer::ByteWriter::with_encoding(&mut source, Encoding::Compact);
+
+        for value in [0, 1, 127, 128, 300, u32::MAX] {
+            writer.write_u32(value);
+        }
+
+        for value in [0, -1, 63, -64, 64, -65, i32::MIN, i32::MAX] {
+            writer.write_i32(value);
+        }
+
+        assert_eq!(&source[..4], &[0x00, 0x01, 0x7F, 0x80]);
+
+        let mut reader = ByteReader::with_encoding(&source, Encoding::Compact);
+
+        for value in [0, 1, 127, 128, 300, u32::MAX] {
+            assert_eq!(reader.read_u32(), Some(value));
+        }
+
+        for value in [0, -1, 63, -64, 64, -65, i32::MIN, i32::MAX] {
+            assert_eq!(reader.read_i32(), Some(value));
+        }
+
+        let source = vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+        assert_eq!(
+            ByteReader::with_encoding(&source, Encoding::Compact).read_u32(),
+            None
+        );
+    }
+
     #[test]
     fn module_reader_read_u32_out_of_bounds() {
         let source = vec![0x89, 0xAB, 0xCD];
@@ -1,10 +1,17 @@
+use crate::Encoding;
+
 pub(crate) struct ByteWriter<'a> {
     source: &'a mut Vec<u8>,
+    encoding: Encoding, // Encoding of u32 and i32 values
 }
 
 impl<'a> ByteWriter<'a> {
     pub fn new(source: &'a mut Vec<u8>) -> ByteWriter<'a> {
-        ByteWriter { source: source }
+        ByteWriter::with_encoding(source, Encoding::Fixed)
+    }
+
+    pub fn with_encoding(source: &'a mut Vec<u8>, encoding: Encoding) -> ByteWriter<'a> {
+        ByteWriter { source, encoding }
     }
 
     pub fn write_byte(&mut self, byte: u8) {
@@ -17,13 +24,49 @@ impl<'a> ByteWriter<'a> {
     }
 
     #[inline]
-    pub fn write_u32(&mut self, value: u32) {
+    pub fn write_u16(&mut self, value: u16) {
         self.source.extend(value.to_be_bytes().iter());
     }
 
+    #[inline]
+    pub fn write_u32(&mut self, value: u32) {
+        match self.encoding {
+            Encoding::Fixed => self.source.extend(value.to_be_bytes().iter()),
+            Encoding::Compact => {
+                // Unsigned LEB128, 7 bits per byte with the high bit set on all but the last
+                let mut value = value;
+
+                while value >= 0x80 {
+                    self.source.push((value as u8 & 0x7F) | 0x80);
+                    value >>= 7;
+                }
+
+                self.source.push(value as u8);
+            }
+        }
+    }
+
     #[inline]
     pub fn write_i32(&mut self, value: i32) {
-        self.source.extend(value.to_be_bytes().iter());
+        match self.encoding {
+            Encoding::Fixed => self.source.extend(value.to_be_bytes().iter()),
+            Encoding::Compact => {
+                // Signed LEB128, stops once the remaining bits only repeat the sign bit
+                let mut value = value;
+
+                loop {
+                    let byte = value as u8 & 0x7F;
+                    value >>= 7;
+
+                    if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+                        self.source.push(byte);
+                        break;
+                    }
+
+                    self.source.push(byte | 0x80);
+                }
+            }
+        }
     }
 
     #[inline]
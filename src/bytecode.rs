@@ -1,3 +1,11 @@
+// How integer operands are written, selected by the container flags
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Fixed, // 4 byte big endian integers
+    Compact, // LEB128 variable length integers
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ByteCode {
@@ -10,10 +18,25 @@ pub enum ByteCode {
     Hi = 0x02,   // Print "Hi"
 
     // Functions
-    Func = 0x03, // Define a function
-    Call = 0x04, // Call a function
+    Func = 0x03,     // Define a function
+    FuncSig = 0x36, // FUNCSIG <len: u32> <name: string> <params: u32> <results: u32> <locals: u32> <typed: u8> <types: [u8; params + results + locals] if typed> <code: [ByteCode; len]> Define a function with a signature
+    Call = 0x04,    // Call a function
+    TailCall = 0x34, // Call a function reusing the frame of the current one
+
+    // Closures
+    MakeClosure = 0x30, // MAKECLOSURE <module: string> <function: string> <count: u32> <captures: [<kind: u8> <index: u32>; count]>
+    GetUpvalue = 0x31,  // Push the value of a captured variable of the current closure
+    SetUpvalue = 0x32, // Store the top element of the stack in a captured variable of the current closure
+    CallClosure = 0x33, // Call the closure on top of the stack CALLCLOSURE <param_count: u32>
+
+    // Globals
+    Global = 0x24, // GLOBAL <len: u32> <name: string> <init: [ByteCode; len]> Declare a module global
+    GetGlobal = 0x25, // Push the value of a global of the current module
+    SetGlobal = 0x26, // Store the top element of the stack in a global of the current module
 
     // Constants
+    Constants = 0x27, // CONSTANTS <count: u32> <values: [<kind: u8> <value>; count]> Constant pool of a module
+    GetConst = 0x28, // Push an entry of the constant pool of the current module GETCONST <index: u32>
     PushConstString = 0x40, // Push a constant string onto the stack PushConstString <len: u32> <string: [u8; len]>
     PushConstInteger = 0x41, // Push a constant integer onto the stack PushConstInt <value: i32>
     PushConstFloat = 0x42,  // Push a constant float onto the stack PushConstFloat <value: f32>
@@ -28,10 +51,22 @@ pub enum ByteCode {
     Allocate = 0x05, // Allocate a new object with the given number of fields on top of the stack
     GetField = 0x06, // Push the value of a field of an object in the top element of the stack
     SetField = 0x07, // Set the value of the top element of the stack into a field of an object on the second element of the stack
+    GetFieldByName = 0x37, // GETFIELDBYNAME <name: string> Push a field of a struct or native object looked up by name
+    SetFieldByName = 0x38, // SETFIELDBYNAME <name: string> Set a field of a struct or native object looked up by name
+    CallMethod = 0x35, // CALLMETHOD <name: string> <param_count: u32> Invoke a method of the native object below the arguments
+    Struct = 0x20, // STRUCT <name: string> <count: u32> <fields: [string; count]> Declare a named struct type
+    New = 0x08, // NEW <name: string> <fields: u32> Allocate a new instance of a named struct type
+    IsInstance = 0x1F, // ISINSTANCE <name: string> Check if the top element of the stack is an instance of a struct type
 
     // Stack manipulation
-    Pop = 0x0B, // Pop the top element of the stack
-    Dup = 0x0C, // Duplicate the top element of the stack
+    Pop = 0x0B,  // Pop the top element of the stack
+    Dup = 0x0C,  // Duplicate the top element of the stack
+    Swap = 0x29, // Swap the two top elements of the stack
+    Over = 0x2A, // Push a copy of the second element of the stack
+    Rot = 0x2B,  // Move the third element of the stack to the top
+    Pick = 0x2C, // PICK <depth: u32> Push a copy of the element at depth (0 is the top)
+    Drop = 0x2D, // DROP <count: u32> Pop count elements from the stack
+    DupN = 0x2E, // DUPN <count: u32> Duplicate the count top elements of the stack
 
     // Arithmetic
     Add = 0x0D, // Add
@@ -51,6 +86,7 @@ pub enum ByteCode {
 
     // Modules
     Module = 0x1B, // Define a module
+    Import = 0x21, // IMPORT <module: string> <count: u32> <functions: [string; count]> Declare the functions a module uses from another one
 
     // Dynamic Module
     LoadModule = 0x19,  // Load a dynamic module
@@ -58,12 +94,15 @@ pub enum ByteCode {
     Alias = 0x1C,       // Alias a function from a dynamic module
 
     // Control flow
-    Return = 0xFE,   // Return from the current function
-    Then = 0xFD,     // THEN <block: [ByteCode]> END Execute a block of code conditionally
+    Return = 0xFE,     // Return from the current function
+    Then = 0xFD,       // THEN <block: [ByteCode]> END Execute a block of code conditionally
     Else = 0xFC, // IF <block: [ByteCode]> ELSE <block: [ByteCode]> END Execute a block of code conditionally
     Loop = 0xFB, // LOOP <block: [ByteCode]> END Execute a block of code in a loop until instructed to break
-    Break = 0xFA, // BREAK Exit the current loop
+    Switch = 0xF8, // SWITCH <count: u32> <cases: [<len: u32> <block: [ByteCode]>; count + 1]> Execute the case selected by an integer, the last block is the default
+    Break = 0xFA,  // BREAK Exit the current loop
     Continue = 0xF9, // CONTINUE Skip to the next iteration of the current loop
+    BreakTo = 0xF7, // BREAKTO <depth: u32> Exit the loop depth levels above the current one
+    ContinueTo = 0xF6, // CONTINUETO <depth: u32> Skip to the next iteration of the loop depth levels above the current one
 }
 
 impl ByteCode {
@@ -74,7 +113,18 @@ impl ByteCode {
             0x01 => Some(ByteCode::Dump),
             0x02 => Some(ByteCode::Hi),
             0x03 => Some(ByteCode::Func),
+            0x36 => Some(ByteCode::FuncSig),
             0x04 => Some(ByteCode::Call),
+            0x34 => Some(ByteCode::TailCall),
+            0x30 => Some(ByteCode::MakeClosure),
+            0x31 => Some(ByteCode::GetUpvalue),
+            0x32 => Some(ByteCode::SetUpvalue),
+            0x33 => Some(ByteCode::CallClosure),
+            0x24 => Some(ByteCode::Global),
+            0x25 => Some(ByteCode::GetGlobal),
+            0x26 => Some(ByteCode::SetGlobal),
+            0x27 => Some(ByteCode::Constants),
+            0x28 => Some(ByteCode::GetConst),
             0x40 => Some(ByteCode::PushConstString),
             0x41 => Some(ByteCode::PushConstInteger),
             0x42 => Some(ByteCode::PushConstFloat),
@@ -85,8 +135,21 @@ impl ByteCode {
             0x05 => Some(ByteCode::Allocate),
             0x06 => Some(ByteCode::GetField),
             0x07 => Some(ByteCode::SetField),
+            0x35 => Some(ByteCode::CallMethod),
+            0x37 => Some(ByteCode::GetFieldByName),
+            0x38 => Some(ByteCode::SetFieldByName),
+            0x20 => Some(ByteCode::Struct),
+            0x21 => Some(ByteCode::Import),
+            0x08 => Some(ByteCode::New),
+            0x1F => Some(ByteCode::IsInstance),
             0x0B => Some(ByteCode::Pop),
             0x0C => Some(ByteCode::Dup),
+            0x29 => Some(ByteCode::Swap),
+            0x2A => Some(ByteCode::Over),
+            0x2B => Some(ByteCode::Rot),
+            0x2C => Some(ByteCode::Pick),
+            0x2D => Some(ByteCode::Drop),
+            0x2E => Some(ByteCode::DupN),
             0x0D => Some(ByteCode::Add),
             0x0E => Some(ByteCode::Sub),
             0x0F => Some(ByteCode::Mul),
@@ -107,7 +170,11 @@ impl ByteCode {
             0xFD => Some(ByteCode::Then),
             0xFC => Some(ByteCode::Else),
             0xFB => Some(ByteCode::Loop),
+            0xF8 => Some(ByteCode::Switch),
             0xFA => Some(ByteCode::Break),
+            0xF9 => Some(ByteCode::Continue),
+            0xF7 => Some(ByteCode::BreakTo),
+            0xF6 => Some(ByteCode::ContinueTo),
             _ => None,
         }
     }
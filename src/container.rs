@@ -0,0 +1,371 @@
+use crate::{
+    byte_reader::ByteReader, byte_writer::ByteWriter, check_opcodes, required_opcodes, Code,
+    Encoding, Instruction,
+};
+
+// File layout, all integers big endian:
+//
+// <magic: [u8; 4]> <format_version: u16> <flags: u16> <checksum: u32> <section_count: u32>
+// <sections: [<kind: u8> <name: string> <offset: u32> <length: u32>; section_count]>
+// <data: [u8]>
+//
+// Offsets are relative to the start of the file, the checksum is the CRC-32 of the whole file
+// with the checksum field set to zero.
+pub const CONTAINER_MAGIC: [u8; 4] = *b"MSBC";
+pub const CONTAINER_FORMAT_VERSION: u16 = 1;
+
+// Header flags
+pub const CONTAINER_FLAG_COMPACT: u16 = 0x0001; // Code section uses LEB128 integers
+const KNOWN_FLAGS: u16 = CONTAINER_FLAG_COMPACT;
+
+const HEADER_SIZE: usize = 16;
+const CHECKSUM_OFFSET: usize = 8;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Code = 0x01,         // Instruction stream, the same encoding as a bare bytecode file
+    Constants = 0x02,    // Shared constant data
+    Debug = 0x03,        // Debug information such as source names and line tables
+    Custom = 0x04,       // Tool specific metadata, identified by the section name
+    Capabilities = 0x05, // Sorted opcodes used by the code section
+}
+
+impl SectionKind {
+    pub fn from_u8(value: u8) -> Option<SectionKind> {
+        match value {
+            0x01 => Some(SectionKind::Code),
+            0x02 => Some(SectionKind::Constants),
+            0x03 => Some(SectionKind::Debug),
+            0x04 => Some(SectionKind::Custom),
+            0x05 => Some(SectionKind::Capabilities),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub kind: SectionKind,
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Container {
+    pub flags: u16,
+    pub sections: Vec<Section>,
+}
+
+impl Container {
+    pub fn new() -> Container {
+        Container::default()
+    }
+
+    // Container with the code section and the opcodes it needs
+    pub fn from_code(code: &Code) -> Container {
+        Container::from_code_with(code, Encoding::Fixed)
+    }
+
+    pub fn from_code_with(code: &Code, encoding: Encoding) -> Container {
+        let mut container = Container::new()
+            .section(
+                SectionKind::Code,
+                "code",
+                Instruction::code_to_bytes_with(code, encoding),
+            )
+            .section(
+                SectionKind::Capabilities,
+                "capabilities",
+                required_opcodes(code),
+            );
+
+        if encoding == Encoding::Compact {
+            container.flags |= CONTAINER_FLAG_COMPACT;
+        }
+
+        container
+    }
+
+    // Encoding of the code section, as selected by the header flags
+    pub fn encoding(&self) -> Encoding {
+        if self.flags & CONTAINER_FLAG_COMPACT != 0 {
+            Encoding::Compact
+        } else {
+            Encoding::Fixed
+        }
+    }
+
+    pub fn section(mut self, kind: SectionKind, name: &str, data: Vec<u8>) -> Container {
+        self.sections.push(Section {
+            kind,
+            name: name.to_string(),
+            data,
+        });
+        self
+    }
+
+    pub fn get_section(&self, kind: SectionKind) -> Option<&Section> {
+        self.sections.iter().find(|section| section.kind == kind)
+    }
+
+    pub fn get_custom(&self, name: &str) -> Option<&Section> {
+        self.sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Custom && section.name == name)
+    }
+
+    // Decode the code section, after checking this runtime supports every opcode it uses
+    pub fn code(&self) -> Result<Code, String> {
+        let Some(section) = self.get_section(SectionKind::Code) else {
+            return Err("Missing code section".to_string());
+        };
+
+        if let Some(capabilities) = self.get_section(SectionKind::Capabilities) {
+            check_opcodes(&capabilities.data)?;
+        }
+
+        Instruction::from_bytecode_with(&section.data, self.encoding())
+    }
+
+    pub fn is_container(bytes: &[u8]) -> bool {
+        bytes.starts_with(&CONTAINER_MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut table = Vec::new();
+        let mut writer = ByteWriter::new(&mut table);
+
+        let table_size: usize = self
+            .sections
+            .iter()
+            .map(|section| 1 + 4 + section.name.len() + 4 + 4)
+            .sum();
+        let mut offset = HEADER_SIZE + table_size;
+
+        for section in self.sections.iter() {
+            writer.write_byte(section.kind as u8);
+            writer.write_string(&section.name);
+            writer.write_u32(offset as u32);
+            writer.write_u32(section.data.len() as u32);
+            offset += section.data.len();
+        }
+
+        let mut bytes = Vec::with_capacity(offset);
+        let mut writer = ByteWriter::new(&mut bytes);
+
+        writer.write_bytes(&CONTAINER_MAGIC.to_vec());
+        writer.write_u16(CONTAINER_FORMAT_VERSION);
+        writer.write_u16(self.flags);
+        writer.write_u32(0); // Checksum, filled below
+        writer.write_u32(self.sections.len() as u32);
+        writer.write_bytes(&table);
+
+        for section in self.sections.iter() {
+            writer.write_bytes(&section.data);
+        }
+
+        let checksum = checksum(&bytes);
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Container, String> {
+        if bytes.len() < CONTAINER_MAGIC.len() || !Container::is_container(bytes) {
+            return Err("Not a bytecode container, invalid magic number".to_string());
+        }
+
+        if bytes.len() < HEADER_SIZE {
+            return Err(format!(
+                "Truncated container, header needs {} bytes, found {}",
+                HEADER_SIZE,
+                bytes.len()
+            ));
+        }
+
+        let mut reader = ByteReader::new(bytes);
+        reader.read_bytes(CONTAINER_MAGIC.len());
+
+        let (Some(version), Some(flags), Some(checksum), Some(count)) = (
+            reader.read_u16(),
+            reader.read_u16(),
+            reader.read_u32(),
+            reader.read_u32(),
+        ) else {
+            return Err("Truncated container header".to_string());
+        };
+
+        if version != CONTAINER_FORMAT_VERSION {
+            return Err(format!(
+                "Unsupported container format version {}, expected {}",
+                version, CONTAINER_FORMAT_VERSION
+            ));
+        }
+
+        if self::checksum(bytes) != checksum {
+            return Err("Container checksum mismatch, the file is corrupted".to_string());
+        }
+
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(format!("Unsupported container flags 0x{:04X}", flags));
+        }
+
+        let mut container = Container {
+            flags,
+            sections: Vec::new(),
+        };
+
+        for index in 0..count {
+            let (Some(kind), Some(name), Some(offset), Some(length)) = (
+                reader.read_byte(),
+                reader.read_string(),
+                reader.read_u32(),
+                reader.read_u32(),
+            ) else {
+                return Err(format!("Truncated section table at section {}", index));
+            };
+
+            let Some(kind) = SectionKind::from_u8(kind) else {
+                return Err(format!("Unknown kind 0x{:02X} of section {}", kind, index));
+            };
+
+            let start = offset as usize;
+            let Some(data) = start
+                .checked_add(length as usize)
+                .and_then(|end| bytes.get(start..end))
+            else {
+                return Err(format!(
+                    "Section \"{}\" extends past the end of the container",
+                    name
+                ));
+            };
+
+            container.sections.push(Section {
+                kind,
+                name,
+                data: data.to_vec(),
+            });
+        }
+
+        Ok(container)
+    }
+}
+
+// Decode either a container or a bare instruction stream
+pub fn read_code(bytes: &[u8]) -> Result<Code, String> {
+    if Container::is_container(bytes) {
+        Container::from_bytes(bytes)?.code()
+    } else {
+        Instruction::from_bytecode(bytes)
+    }
+}
+
+// CRC-32 of the whole file, the checksum field is read as zeros
+fn checksum(bytes: &[u8]) -> u32 {
+    let crc = crc32_update(0xFFFFFFFF, &bytes[..CHECKSUM_OFFSET]);
+    let crc = crc32_update(crc, &[0; 4]);
+    !crc32_update(crc, &bytes[CHECKSUM_OFFSET + 4..])
+}
+
+// CRC-32 (IEEE 802.3) of more bytes, starts from 0xFFFFFFFF and the result is inverted
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn container_round_trip() {
+        let code = assemble("(mod main (fn run (i32.const 1)))").unwrap();
+        let container = Container::from_code(&code)
+            .section(SectionKind::Debug, "lines", vec![1, 2, 3])
+            .section(SectionKind::Custom, "author", b"me".to_vec());
+
+        let bytes = container.to_bytes();
+
+        assert_eq!(!crc32_update(0xFFFFFFFF, b"123456789"), 0xCBF43926);
+        assert_eq!(Container::from_bytes(&bytes).unwrap(), container);
+        // Instructions compare by kind only, compare their encoding instead
+        assert_eq!(
+            Instruction::code_to_bytes(&read_code(&bytes).unwrap()),
+            Instruction::code_to_bytes(&code)
+        );
+        assert_eq!(
+            Container::from_bytes(&bytes)
+                .unwrap()
+                .get_custom("author")
+                .unwrap()
+                .data,
+            b"me"
+        );
+    }
+
+    #[test]
+    fn compact_encoding_round_trip() {
+        let code = assemble(
+            "(mod main (global g (i32.const -5)) (fn run (str.const \"hi\") (i32.const 300) (then (global.get g) else (local.get 0))))",
+        )
+        .unwrap();
+
+        let fixed = Container::from_code(&code);
+        let compact = Container::from_code_with(&code, Encoding::Compact);
+        let bytes = compact.to_bytes();
+
+        assert_eq!(compact.encoding(), Encoding::Compact);
+        assert!(
+            compact.get_section(SectionKind::Code).unwrap().data.len()
+                < fixed.get_section(SectionKind::Code).unwrap().data.len()
+        );
+        assert_eq!(
+            Instruction::code_to_bytes(&read_code(&bytes).unwrap()),
+            Instruction::code_to_bytes(&code)
+        );
+    }
+
+    #[test]
+    fn container_reports_bad_files() {
+        let bytes = Container::from_code(&assemble("(mod main)").unwrap()).to_bytes();
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+
+        // The header is covered too, flags can't be flipped without the checksum noticing
+        let mut flags = bytes.clone();
+        flags[7] ^= CONTAINER_FLAG_COMPACT as u8;
+
+        let error = |bytes: Vec<u8>| Container::from_bytes(&bytes).unwrap_err();
+
+        assert_eq!(
+            error(b"\x7FELF".to_vec()),
+            "Not a bytecode container, invalid magic number"
+        );
+        assert_eq!(
+            error(bytes[..10].to_vec()),
+            "Truncated container, header needs 16 bytes, found 10"
+        );
+        assert_eq!(
+            error(bytes[..bytes.len() - 1].to_vec()),
+            "Container checksum mismatch, the file is corrupted"
+        );
+        assert_eq!(
+            error(corrupted),
+            "Container checksum mismatch, the file is corrupted"
+        );
+        assert_eq!(
+            error(flags),
+            "Container checksum mismatch, the file is corrupted"
+        );
+    }
+}
@@ -1,11 +1,201 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path};
 
 use libloading::Library;
 
-use crate::Value;
+use crate::{
+    call_plugin_function, NativeSignature, PluginAbiVersion, PluginFunction, PluginManifest,
+    PluginManifestFn, Value, PLUGIN_ABI_VERSION, PLUGIN_ABI_VERSION_SYMBOL, PLUGIN_MANIFEST_SYMBOL,
+    PLUGIN_VARIADIC,
+};
+
+// Host function callable from scripts, either a plugin symbol or a registered closure
+pub type NativeFunction = Box<dyn FnMut(Vec<Value>) -> Result<Option<Value>, String> + Send>;
 
 pub struct DyModule {
     pub name: String,
-    pub lib: Library,
-    pub fns: HashMap<String, Box<fn(Vec<Value>) -> Option<Value>>>,
+    pub fns: HashMap<String, NativeFunction>,
+    pub signatures: HashMap<String, NativeSignature>, // Declared signatures, checked on call
+    pub lib: Option<Library>, // Declared last so functions are dropped before the library
+}
+
+impl DyModule {
+    // Load a plugin library checking that it was built for the current plugin ABI
+    pub fn load(name: &str) -> Result<DyModule, String> {
+        DyModule::load_from(name, Path::new(name))
+    }
+
+    // Load a plugin library from a resolved path, the module is still called by name
+    pub fn load_from(name: &str, path: &Path) -> Result<DyModule, String> {
+        let lib = unsafe {
+            Library::new(path)
+                .map_err(|e| format!("Failed to load \"{}\": {}", path.display(), e))?
+        };
+
+        let version = unsafe {
+            let Ok(symbol) = lib.get::<PluginAbiVersion>(PLUGIN_ABI_VERSION_SYMBOL.as_bytes())
+            else {
+                return Err(format!(
+                    "\"{}\" is not a plugin, missing symbol {}",
+                    name, PLUGIN_ABI_VERSION_SYMBOL
+                ));
+            };
+
+            symbol()
+        };
+
+        if version != PLUGIN_ABI_VERSION {
+            return Err(format!(
+                "Plugin \"{}\" uses ABI version {}, expected {}",
+                name, version, PLUGIN_ABI_VERSION
+            ));
+        }
+
+        Ok(DyModule {
+            name: name.to_string(),
+            fns: HashMap::new(),
+            signatures: HashMap::new(),
+            lib: Some(lib),
+        })
+    }
+
+    // Import a function exported by the plugin, optionally under another name
+    pub fn load_function(&mut self, symbol: &str, alias: Option<&str>) -> Result<(), String> {
+        let Some(lib) = &self.lib else {
+            return Err(format!("Module \"{}\" is not a plugin", self.name));
+        };
+
+        let function: PluginFunction = unsafe {
+            *lib.get::<PluginFunction>(symbol.as_bytes())
+                .map_err(|e| e.to_string())?
+        };
+
+        self.fns.insert(
+            alias.unwrap_or(symbol).to_string(),
+            Box::new(move |args| unsafe { call_plugin_function(function, &args) }),
+        );
+        Ok(())
+    }
+
+    // Import functions from the manifest exported by the plugin, returns false when the
+    // plugin has no manifest and functions must be loaded one by one with (fn.get)
+    pub fn import_manifest(&mut self, names: &[(String, Option<String>)]) -> Result<bool, String> {
+        let Some(lib) = &self.lib else {
+            return Ok(false);
+        };
+
+        let manifest = unsafe {
+            let Ok(symbol) = lib.get::<PluginManifestFn>(PLUGIN_MANIFEST_SYMBOL.as_bytes()) else {
+                return Ok(false);
+            };
+
+            symbol()
+        };
+
+        // The manifest lives in the library, which outlives this call
+        let Some(manifest) = (unsafe { manifest.as_ref() }) else {
+            return Err(format!("Plugin \"{}\" returned a null manifest", self.name));
+        };
+
+        self.import_functions(manifest, names)?;
+        Ok(true)
+    }
+
+    // Import the functions listed in a manifest, all of them when no names are given,
+    // otherwise only the (name, alias) pairs requested
+    pub fn import_functions(
+        &mut self,
+        manifest: &PluginManifest,
+        names: &[(String, Option<String>)],
+    ) -> Result<(), String> {
+        let functions = if manifest.functions.is_null() {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(manifest.functions, manifest.function_count) }
+        };
+
+        let mut available = HashMap::new();
+
+        for info in functions.iter() {
+            let name = unsafe { info.name.as_str() }
+                .map(str::to_string)
+                .ok_or_else(|| format!("Plugin \"{}\" has an invalid function name", self.name))?;
+
+            let param_types = if info.param_types.is_null() || info.param_count == PLUGIN_VARIADIC {
+                Vec::new()
+            } else {
+                unsafe {
+                    std::slice::from_raw_parts(info.param_types, info.param_count as usize).to_vec()
+                }
+            };
+
+            let signature = NativeSignature {
+                param_count: (info.param_count != PLUGIN_VARIADIC).then_some(info.param_count),
+                param_types,
+                result_type: info.result_type,
+            };
+
+            available.insert(name, (info.function, signature));
+        }
+
+        let selected: Vec<(String, String)> = if names.is_empty() {
+            available
+                .keys()
+                .map(|name| (name.clone(), name.clone()))
+                .collect()
+        } else {
+            let missing: Vec<&str> = names
+                .iter()
+                .filter(|(name, _)| !available.contains_key(name))
+                .map(|(name, _)| name.as_str())
+                .collect();
+
+            if !missing.is_empty() {
+                let mut exported: Vec<&String> = available.keys().collect();
+                exported.sort();
+
+                return Err(format!(
+                    "Plugin \"{}\" does not export {}, available functions: {}",
+                    self.name,
+                    missing.join(", "),
+                    exported
+                        .iter()
+                        .map(|name| name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+
+            names
+                .iter()
+                .map(|(name, alias)| (name.clone(), alias.clone().unwrap_or(name.clone())))
+                .collect()
+        };
+
+        for (name, alias) in selected {
+            let (function, signature) = available[&name].clone();
+
+            self.fns.insert(
+                alias.clone(),
+                Box::new(move |args| unsafe { call_plugin_function(function, &args) }),
+            );
+            self.signatures.insert(alias, signature);
+        }
+
+        Ok(())
+    }
+
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Option<Value>, String> {
+        if let Some(signature) = self.signatures.get(name) {
+            signature.check(name, &args)?;
+        }
+
+        let Some(function) = self.fns.get_mut(name) else {
+            return Err(format!(
+                "Function \"{}\" not found in \"{}\"",
+                name, self.name
+            ));
+        };
+
+        function(args)
+    }
 }
@@ -1,6 +1,32 @@
-use crate::instruction::Code;
+use crate::{instruction::Code, Type};
+
+// Declared shape of a function frame, functions without one accept any arguments
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Signature {
+    pub params: u32,            // Arguments stored in the first locals
+    pub results: u32,           // Values left on the stack when returning
+    pub locals: u32,            // Locals after the parameters
+    pub param_types: Vec<Type>, // Types of the parameters, empty when untyped
+    pub result_types: Vec<Type>,
+    pub local_types: Vec<Type>,
+}
+
+impl Signature {
+    // Number of locals of a frame, parameters included
+    pub fn frame_size(&self) -> usize {
+        self.params as usize + self.locals as usize
+    }
+
+    pub fn is_typed(&self) -> bool {
+        !self.param_types.is_empty()
+            || !self.result_types.is_empty()
+            || !self.local_types.is_empty()
+    }
+}
 
 pub struct Function {
     pub name: String,
+    pub signature: Option<Signature>,
     pub code: Code,
+    pub verified: bool, // Type checked at load time, the VM skips argument checks
 }
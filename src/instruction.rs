@@ -1,6 +1,9 @@
 use std::hash::Hash;
 
-use crate::{byte_reader::ByteReader, byte_writer::ByteWriter, sexpr::SExpr, ByteCode};
+use crate::{
+    byte_reader::ByteReader, byte_writer::ByteWriter, scope::Scope, sexpr::SExpr, ByteCode,
+    Encoding, Signature, Type,
+};
 
 #[derive(Debug, Clone)]
 pub enum Instruction {
@@ -19,6 +22,7 @@ pub enum Instruction {
     // Functions
     Fn {
         name: String,
+        signature: Option<Signature>,
         code: Code,
     },
     Call {
@@ -26,8 +30,47 @@ pub enum Instruction {
         function: String,
         param_count: u32,
     },
+    TailCall {
+        module: String,
+        function: String,
+        param_count: u32,
+    },
+
+    // Closures
+    MakeClosure {
+        module: String,
+        function: String,
+        captures: Vec<Capture>,
+    },
+    GetUpvalue {
+        index: u32,
+    },
+    SetUpvalue {
+        index: u32,
+    },
+    CallClosure {
+        param_count: u32,
+    },
+
+    // Globals
+    Global {
+        name: String,
+        init: Code,
+    },
+    GetGlobal {
+        index: u32,
+    },
+    SetGlobal {
+        index: u32,
+    },
 
     // Constants
+    Constants {
+        values: Vec<Constant>,
+    },
+    GetConst {
+        index: u32,
+    },
     PushConstString {
         value: String,
     },
@@ -56,16 +99,49 @@ pub enum Instruction {
     Allocate {
         fields: u32,
     },
+    Struct {
+        name: String,
+        fields: Vec<String>,
+    },
+    New {
+        name: String,
+        fields: u32,
+    },
+    IsInstance {
+        name: String,
+    },
     GetField {
         index: u32,
     },
     SetField {
         index: u32,
     },
+    GetFieldByName {
+        name: String,
+    },
+    SetFieldByName {
+        name: String,
+    },
+    CallMethod {
+        name: String,
+        param_count: u32,
+    },
 
     // Stack manipulation
     Pop,
     Dup,
+    Swap,
+    Over,
+    Rot,
+    Pick {
+        depth: u32,
+    },
+    Drop {
+        count: u32,
+    },
+    DupN {
+        count: u32,
+    },
 
     // Arithmetic
     Add,
@@ -89,6 +165,11 @@ pub enum Instruction {
         code: Code,
     },
 
+    Import {
+        module: String,
+        functions: Vec<String>,
+    },
+
     // Dynamic Module
     LoadModule {
         name: String,
@@ -108,115 +189,37 @@ pub enum Instruction {
     Loop {
         block: Code,
     },
-    Break,
-    Continue,
+    Switch {
+        cases: Vec<Code>,
+        default: Code,
+    },
+    Break {
+        depth: u32, // Number of enclosing loops to leave before the innermost one
+    },
+    Continue {
+        depth: u32,
+    },
+}
+
+// How a closure captures a local variable of the enclosing function
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    Value(u32), // Copy the current value of the local
+    Cell(u32),  // Share the local with the enclosing function through a cell
+}
+
+// An entry of a module constant pool
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    String(String),
+    Integer(i32),
+    Float(f32),
+    Boolean(bool),
 }
 
 impl PartialEq<Self> for Instruction {
     fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Instruction::None, Instruction::None) => true,
-            (
-                Instruction::Version {
-                    major: _a,
-                    minor: _b,
-                    patch: _c,
-                },
-                Instruction::Version {
-                    major: _x,
-                    minor: _y,
-                    patch: _z,
-                },
-            ) => true,
-            (Instruction::Dump, Instruction::Dump) => true,
-            (Instruction::Hi, Instruction::Hi) => true,
-            (Instruction::Fn { name: _a, code: _b }, Instruction::Fn { name: _x, code: _y }) => {
-                true
-            }
-            (
-                Instruction::Call {
-                    module: _a,
-                    function: _b,
-                    param_count: _c,
-                },
-                Instruction::Call {
-                    module: _x,
-                    function: _y,
-                    param_count: _z,
-                },
-            ) => true,
-            (
-                Instruction::PushConstString { value: _a },
-                Instruction::PushConstString { value: _x },
-            ) => true,
-            (
-                Instruction::PushConstInteger { value: _a },
-                Instruction::PushConstInteger { value: _x },
-            ) => true,
-            (
-                Instruction::PushConstFloat { value: _a },
-                Instruction::PushConstFloat { value: _x },
-            ) => true,
-            (
-                Instruction::PushConstBoolean { value: _a },
-                Instruction::PushConstBoolean { value: _x },
-            ) => true,
-            (Instruction::GetLocal { index: _a }, Instruction::GetLocal { index: _x }) => true,
-            (Instruction::SetLocal { index: _a }, Instruction::SetLocal { index: _x }) => true,
-            (Instruction::ReserveLocal { size: _a }, Instruction::ReserveLocal { size: _x }) => {
-                true
-            }
-            (Instruction::Allocate { fields: _a }, Instruction::Allocate { fields: _x }) => true,
-            (Instruction::GetField { index: _a }, Instruction::GetField { index: _x }) => true,
-            (Instruction::SetField { index: _a }, Instruction::SetField { index: _x }) => true,
-            (Instruction::Pop, Instruction::Pop) => true,
-            (Instruction::Dup, Instruction::Dup) => true,
-            (Instruction::Add, Instruction::Add) => true,
-            (Instruction::Sub, Instruction::Sub) => true,
-            (Instruction::Mul, Instruction::Mul) => true,
-            (Instruction::Div, Instruction::Div) => true,
-            (Instruction::Inc, Instruction::Inc) => true,
-            (Instruction::Dec, Instruction::Dec) => true,
-            (Instruction::Eq, Instruction::Eq) => true,
-            (Instruction::Ne, Instruction::Ne) => true,
-            (Instruction::Lt, Instruction::Lt) => true,
-            (Instruction::Le, Instruction::Le) => true,
-            (Instruction::Gt, Instruction::Gt) => true,
-            (Instruction::Ge, Instruction::Ge) => true,
-            (
-                Instruction::Module { name: _a, code: _b },
-                Instruction::Module { name: _x, code: _y },
-            ) => true,
-            (
-                Instruction::LoadModule { name: _a, code: _b },
-                Instruction::LoadModule { name: _x, code: _y },
-            ) => true,
-            (
-                Instruction::GetFunction {
-                    name: _a,
-                    alias: _b,
-                },
-                Instruction::GetFunction {
-                    name: _x,
-                    alias: _y,
-                },
-            ) => true,
-            (Instruction::Return, Instruction::Return) => true,
-            (
-                Instruction::Then {
-                    then_block: _a,
-                    else_block: _b,
-                },
-                Instruction::Then {
-                    then_block: _x,
-                    else_block: _y,
-                },
-            ) => true,
-            (Instruction::Loop { block: _a }, Instruction::Loop { block: _x }) => true,
-            (Instruction::Break, Instruction::Break) => true,
-            (Instruction::Continue, Instruction::Continue) => true,
-            _ => false,
-        }
+        std::mem::discriminant(self) == std::mem::discriminant(other)
     }
 }
 
@@ -233,7 +236,11 @@ impl Hash for Instruction {
             } => 1.hash(state),
             Instruction::Dump => 2.hash(state),
             Instruction::Hi => 3.hash(state),
-            Instruction::Fn { name: _, code: _ } => 4.hash(state),
+            Instruction::Fn {
+                name: _,
+                signature: _,
+                code: _,
+            } => 4.hash(state),
             Instruction::Call {
                 module: _,
                 function: _,
@@ -272,8 +279,49 @@ impl Hash for Instruction {
                 else_block: _,
             } => 34.hash(state),
             Instruction::Loop { block: _ } => 35.hash(state),
-            Instruction::Break => 36.hash(state),
-            Instruction::Continue => 37.hash(state),
+            Instruction::Break { depth: _ } => 36.hash(state),
+            Instruction::Continue { depth: _ } => 37.hash(state),
+            Instruction::MakeClosure {
+                module: _,
+                function: _,
+                captures: _,
+            } => 38.hash(state),
+            Instruction::GetUpvalue { index: _ } => 39.hash(state),
+            Instruction::SetUpvalue { index: _ } => 40.hash(state),
+            Instruction::CallClosure { param_count: _ } => 41.hash(state),
+            Instruction::Struct { name: _, fields: _ } => 42.hash(state),
+            Instruction::New { name: _, fields: _ } => 43.hash(state),
+            Instruction::IsInstance { name: _ } => 44.hash(state),
+            Instruction::Global { name: _, init: _ } => 45.hash(state),
+            Instruction::GetGlobal { index: _ } => 46.hash(state),
+            Instruction::SetGlobal { index: _ } => 47.hash(state),
+            Instruction::Constants { values: _ } => 48.hash(state),
+            Instruction::GetConst { index: _ } => 49.hash(state),
+            Instruction::Swap => 50.hash(state),
+            Instruction::Over => 51.hash(state),
+            Instruction::Rot => 52.hash(state),
+            Instruction::Pick { depth: _ } => 53.hash(state),
+            Instruction::Drop { count: _ } => 54.hash(state),
+            Instruction::DupN { count: _ } => 55.hash(state),
+            Instruction::Switch {
+                cases: _,
+                default: _,
+            } => 56.hash(state),
+            Instruction::TailCall {
+                module: _,
+                function: _,
+                param_count: _,
+            } => 57.hash(state),
+            Instruction::CallMethod {
+                name: _,
+                param_count: _,
+            } => 58.hash(state),
+            Instruction::Import {
+                module: _,
+                functions: _,
+            } => 59.hash(state),
+            Instruction::GetFieldByName { name: _ } => 60.hash(state),
+            Instruction::SetFieldByName { name: _ } => 61.hash(state),
         }
     }
 }
@@ -281,9 +329,13 @@ impl Hash for Instruction {
 pub type Code = Vec<Instruction>;
 
 impl<'a> Instruction {
-    pub fn from_bytecode(bytecode: &'a Vec<u8>) -> Result<Code, String> {
+    pub fn from_bytecode(bytecode: &'a [u8]) -> Result<Code, String> {
+        Instruction::from_bytecode_with(bytecode, Encoding::Fixed)
+    }
+
+    pub fn from_bytecode_with(bytecode: &'a [u8], encoding: Encoding) -> Result<Code, String> {
         let mut code = Vec::new();
-        let mut reader = ByteReader::new(bytecode);
+        let mut reader = ByteReader::with_encoding(bytecode, encoding);
 
         while let Some(byte) = reader.read_byte() {
             let Some(byte) = ByteCode::from_u8(byte) else {
@@ -306,9 +358,9 @@ impl<'a> Instruction {
                     };
 
                     code.push(Instruction::Version {
-                        major: major,
-                        minor: minor,
-                        patch: patch,
+                        major,
+                        minor,
+                        patch,
                     });
                 }
                 ByteCode::Dump => code.push(Instruction::Dump),
@@ -328,8 +380,60 @@ impl<'a> Instruction {
 
                     // This can be done in multy threads
                     code.push(Instruction::Fn {
-                        name: name,
-                        code: Instruction::from_bytecode(&fn_code)?,
+                        name,
+                        signature: None,
+                        code: Instruction::from_bytecode_with(fn_code, encoding)?,
+                    });
+                }
+                ByteCode::FuncSig => {
+                    let Some(lenght) = reader.read_u32() else {
+                        return Err("Expected function code length".to_string());
+                    };
+
+                    let Some(name) = reader.read_string() else {
+                        return Err("Expected function name".to_string());
+                    };
+
+                    let (Some(params), Some(results), Some(locals), Some(typed)) = (
+                        reader.read_u32(),
+                        reader.read_u32(),
+                        reader.read_u32(),
+                        reader.read_byte(),
+                    ) else {
+                        return Err("Expected function signature".to_string());
+                    };
+
+                    let mut signature = Signature {
+                        params,
+                        results,
+                        locals,
+                        ..Default::default()
+                    };
+
+                    if typed != 0 {
+                        for (types, count) in [
+                            (&mut signature.param_types, params),
+                            (&mut signature.result_types, results),
+                            (&mut signature.local_types, locals),
+                        ] {
+                            for _ in 0..count {
+                                let Some(value) = reader.read_byte().and_then(Type::from_u8) else {
+                                    return Err("Expected type".to_string());
+                                };
+
+                                types.push(value);
+                            }
+                        }
+                    }
+
+                    let Some(fn_code) = reader.read_bytes(lenght as usize) else {
+                        return Err("Expected function code".to_string());
+                    };
+
+                    code.push(Instruction::Fn {
+                        name,
+                        signature: Some(signature),
+                        code: Instruction::from_bytecode_with(fn_code, encoding)?,
                     });
                 }
                 ByteCode::Call => {
@@ -351,68 +455,298 @@ impl<'a> Instruction {
                         param_count,
                     });
                 }
+                ByteCode::TailCall => {
+                    let Some(module) = reader.read_string() else {
+                        return Err("Expected module name".to_string());
+                    };
+
+                    let Some(function) = reader.read_string() else {
+                        return Err("Expected function name".to_string());
+                    };
+
+                    let Some(param_count) = reader.read_u32() else {
+                        return Err("Expected parameter count".to_string());
+                    };
+
+                    code.push(Instruction::TailCall {
+                        module,
+                        function,
+                        param_count,
+                    });
+                }
+                ByteCode::MakeClosure => {
+                    let Some(module) = reader.read_string() else {
+                        return Err("Expected module name".to_string());
+                    };
+
+                    let Some(function) = reader.read_string() else {
+                        return Err("Expected function name".to_string());
+                    };
+
+                    let Some(count) = reader.read_u32() else {
+                        return Err("Expected capture count".to_string());
+                    };
+
+                    let mut captures = Vec::new();
+
+                    for _ in 0..count {
+                        let Some(kind) = reader.read_byte() else {
+                            return Err("Expected capture kind".to_string());
+                        };
+
+                        let Some(index) = reader.read_u32() else {
+                            return Err("Expected capture index".to_string());
+                        };
+
+                        captures.push(match kind {
+                            0 => Capture::Value(index),
+                            1 => Capture::Cell(index),
+                            _ => return Err(format!("Invalid capture kind: {}", kind)),
+                        });
+                    }
+
+                    code.push(Instruction::MakeClosure {
+                        module,
+                        function,
+                        captures,
+                    });
+                }
+                ByteCode::GetUpvalue => {
+                    let Some(index) = reader.read_u32() else {
+                        return Err("Expected upvalue index".to_string());
+                    };
+
+                    code.push(Instruction::GetUpvalue { index });
+                }
+                ByteCode::SetUpvalue => {
+                    let Some(index) = reader.read_u32() else {
+                        return Err("Expected upvalue index".to_string());
+                    };
+
+                    code.push(Instruction::SetUpvalue { index });
+                }
+                ByteCode::CallClosure => {
+                    let Some(param_count) = reader.read_u32() else {
+                        return Err("Expected parameter count".to_string());
+                    };
+
+                    code.push(Instruction::CallClosure { param_count });
+                }
+                ByteCode::Global => {
+                    let Some(lenght) = reader.read_u32() else {
+                        return Err("Expected global init code length".to_string());
+                    };
+
+                    let Some(name) = reader.read_string() else {
+                        return Err("Expected global name".to_string());
+                    };
+
+                    let Some(init) = reader.read_bytes(lenght as usize) else {
+                        return Err("Expected global init code".to_string());
+                    };
+
+                    code.push(Instruction::Global {
+                        name,
+                        init: Instruction::from_bytecode_with(init, encoding)?,
+                    });
+                }
+                ByteCode::GetGlobal => {
+                    let Some(index) = reader.read_u32() else {
+                        return Err("Expected global index".to_string());
+                    };
+
+                    code.push(Instruction::GetGlobal { index });
+                }
+                ByteCode::SetGlobal => {
+                    let Some(index) = reader.read_u32() else {
+                        return Err("Expected global index".to_string());
+                    };
+
+                    code.push(Instruction::SetGlobal { index });
+                }
+                ByteCode::Constants => {
+                    let Some(count) = reader.read_u32() else {
+                        return Err("Expected constant count".to_string());
+                    };
+
+                    let mut values = Vec::new();
+
+                    for _ in 0..count {
+                        let Some(kind) = reader.read_byte() else {
+                            return Err("Expected constant kind".to_string());
+                        };
+
+                        let constant = match kind {
+                            0 => reader.read_string().map(Constant::String),
+                            1 => reader.read_i32().map(Constant::Integer),
+                            2 => reader.read_f32().map(Constant::Float),
+                            3 => reader.read_bool().map(Constant::Boolean),
+                            _ => return Err(format!("Invalid constant kind: {}", kind)),
+                        };
+
+                        let Some(constant) = constant else {
+                            return Err("Expected constant value".to_string());
+                        };
+
+                        values.push(constant);
+                    }
+
+                    code.push(Instruction::Constants { values });
+                }
+                ByteCode::GetConst => {
+                    let Some(index) = reader.read_u32() else {
+                        return Err("Expected constant index".to_string());
+                    };
+
+                    code.push(Instruction::GetConst { index });
+                }
                 ByteCode::PushConstString => {
                     let Some(value) = reader.read_string() else {
                         return Err("Expected string value".to_string());
                     };
 
-                    code.push(Instruction::PushConstString { value: value });
+                    code.push(Instruction::PushConstString { value });
                 }
                 ByteCode::PushConstInteger => {
                     let Some(value) = reader.read_i32() else {
                         return Err("Expected integer value".to_string());
                     };
 
-                    code.push(Instruction::PushConstInteger { value: value });
+                    code.push(Instruction::PushConstInteger { value });
                 }
                 ByteCode::PushConstFloat => {
                     let Some(value) = reader.read_f32() else {
                         return Err("Expected float value".to_string());
                     };
 
-                    code.push(Instruction::PushConstFloat { value: value });
+                    code.push(Instruction::PushConstFloat { value });
                 }
                 ByteCode::PushConstBoolean => {
                     let Some(value) = reader.read_bool() else {
                         return Err("Expected boolean value".to_string());
                     };
 
-                    code.push(Instruction::PushConstBoolean { value: value });
+                    code.push(Instruction::PushConstBoolean { value });
                 }
                 ByteCode::GetLocal => {
                     let Some(index) = reader.read_u32() else {
                         return Err("Expected local index".to_string());
                     };
 
-                    code.push(Instruction::GetLocal { index: index });
+                    code.push(Instruction::GetLocal { index });
                 }
                 ByteCode::Allocate => {
                     let Some(fields) = reader.read_u32() else {
                         return Err("Expected number of fields".to_string());
                     };
 
-                    code.push(Instruction::Allocate { fields: fields });
+                    code.push(Instruction::Allocate { fields });
+                }
+                ByteCode::Import => {
+                    let Some(module) = reader.read_string() else {
+                        return Err("Expected module name".to_string());
+                    };
+
+                    let Some(count) = reader.read_u32() else {
+                        return Err("Expected number of functions".to_string());
+                    };
+
+                    let mut functions = Vec::new();
+
+                    for _ in 0..count {
+                        let Some(function) = reader.read_string() else {
+                            return Err("Expected function name".to_string());
+                        };
+
+                        functions.push(function);
+                    }
+
+                    code.push(Instruction::Import { module, functions });
+                }
+                ByteCode::Struct => {
+                    let Some(name) = reader.read_string() else {
+                        return Err("Expected struct name".to_string());
+                    };
+
+                    let Some(count) = reader.read_u32() else {
+                        return Err("Expected number of fields".to_string());
+                    };
+
+                    let mut fields = Vec::new();
+
+                    for _ in 0..count {
+                        let Some(field) = reader.read_string() else {
+                            return Err("Expected field name".to_string());
+                        };
+
+                        fields.push(field);
+                    }
+
+                    code.push(Instruction::Struct { name, fields });
+                }
+                ByteCode::New => {
+                    let Some(name) = reader.read_string() else {
+                        return Err("Expected struct name".to_string());
+                    };
+
+                    let Some(fields) = reader.read_u32() else {
+                        return Err("Expected number of fields".to_string());
+                    };
+
+                    code.push(Instruction::New { name, fields });
+                }
+                ByteCode::IsInstance => {
+                    let Some(name) = reader.read_string() else {
+                        return Err("Expected struct name".to_string());
+                    };
+
+                    code.push(Instruction::IsInstance { name });
                 }
                 ByteCode::GetField => {
                     let Some(index) = reader.read_u32() else {
                         return Err("Expected field index".to_string());
                     };
 
-                    code.push(Instruction::GetField { index: index });
+                    code.push(Instruction::GetField { index });
                 }
                 ByteCode::SetField => {
                     let Some(index) = reader.read_u32() else {
                         return Err("Expected field index".to_string());
                     };
 
-                    code.push(Instruction::SetField { index: index });
+                    code.push(Instruction::SetField { index });
+                }
+                ByteCode::GetFieldByName => {
+                    let Some(name) = reader.read_string() else {
+                        return Err("Expected field name".to_string());
+                    };
+
+                    code.push(Instruction::GetFieldByName { name });
+                }
+                ByteCode::SetFieldByName => {
+                    let Some(name) = reader.read_string() else {
+                        return Err("Expected field name".to_string());
+                    };
+
+                    code.push(Instruction::SetFieldByName { name });
+                }
+                ByteCode::CallMethod => {
+                    let Some(name) = reader.read_string() else {
+                        return Err("Expected method name".to_string());
+                    };
+
+                    let Some(param_count) = reader.read_u32() else {
+                        return Err("Expected parameter count".to_string());
+                    };
+
+                    code.push(Instruction::CallMethod { name, param_count });
                 }
                 ByteCode::SetLocal => {
                     let Some(index) = reader.read_u32() else {
                         return Err("Expected local index".to_string());
                     };
 
-                    code.push(Instruction::SetLocal { index: index });
+                    code.push(Instruction::SetLocal { index });
                 }
                 ByteCode::ReserveLocal => {
                     let Some(index) = reader.read_u32() else {
@@ -423,6 +757,30 @@ impl<'a> Instruction {
                 }
                 ByteCode::Pop => code.push(Instruction::Pop),
                 ByteCode::Dup => code.push(Instruction::Dup),
+                ByteCode::Swap => code.push(Instruction::Swap),
+                ByteCode::Over => code.push(Instruction::Over),
+                ByteCode::Rot => code.push(Instruction::Rot),
+                ByteCode::Pick => {
+                    let Some(depth) = reader.read_u32() else {
+                        return Err("Expected stack depth".to_string());
+                    };
+
+                    code.push(Instruction::Pick { depth });
+                }
+                ByteCode::Drop => {
+                    let Some(count) = reader.read_u32() else {
+                        return Err("Expected element count".to_string());
+                    };
+
+                    code.push(Instruction::Drop { count });
+                }
+                ByteCode::DupN => {
+                    let Some(count) = reader.read_u32() else {
+                        return Err("Expected element count".to_string());
+                    };
+
+                    code.push(Instruction::DupN { count });
+                }
                 ByteCode::Add => code.push(Instruction::Add),
                 ByteCode::Sub => code.push(Instruction::Sub),
                 ByteCode::Mul => code.push(Instruction::Mul),
@@ -449,8 +807,8 @@ impl<'a> Instruction {
                     };
 
                     code.push(Instruction::Module {
-                        name: name,
-                        code: Instruction::from_bytecode(&module_code)?,
+                        name,
+                        code: Instruction::from_bytecode_with(module_code, encoding)?,
                     });
                 }
                 ByteCode::LoadModule => {
@@ -467,8 +825,8 @@ impl<'a> Instruction {
                     };
 
                     code.push(Instruction::LoadModule {
-                        name: name,
-                        code: Instruction::from_bytecode(&module_code)?,
+                        name,
+                        code: Instruction::from_bytecode_with(module_code, encoding)?,
                     });
                 }
                 ByteCode::GetFunction => {
@@ -484,18 +842,15 @@ impl<'a> Instruction {
                             };
 
                             code.push(Instruction::GetFunction {
-                                name: name,
+                                name,
                                 alias: Some(alias),
                             });
-                            break;
+                            continue;
                         }
                     }
 
                     reader.restore_position();
-                    code.push(Instruction::GetFunction {
-                        name: name,
-                        alias: None,
-                    });
+                    code.push(Instruction::GetFunction { name, alias: None });
                 }
                 ByteCode::Alias => {
                     return Err("Invalid instruction (as) outside of function".to_string());
@@ -510,7 +865,7 @@ impl<'a> Instruction {
                         return Err("Expected block code".to_string());
                     };
 
-                    let then_block = Instruction::from_bytecode(&block)?;
+                    let then_block = Instruction::from_bytecode_with(block, encoding)?;
                     let mut else_block = Vec::new();
 
                     reader.save_position();
@@ -524,7 +879,7 @@ impl<'a> Instruction {
                                 return Err("Expected block code".to_string());
                             };
 
-                            else_block = Instruction::from_bytecode(&block)?;
+                            else_block = Instruction::from_bytecode_with(block, encoding)?;
                         } else {
                             reader.restore_position();
                         }
@@ -534,7 +889,7 @@ impl<'a> Instruction {
 
                     code.push(Instruction::Then {
                         then_block,
-                        else_block: else_block,
+                        else_block,
                     });
                 }
                 ByteCode::Else => {
@@ -550,19 +905,133 @@ impl<'a> Instruction {
                     };
 
                     code.push(Instruction::Loop {
-                        block: Instruction::from_bytecode(&block)?,
+                        block: Instruction::from_bytecode_with(block, encoding)?,
                     });
                 }
-                ByteCode::Break => code.push(Instruction::Break),
-                ByteCode::Continue => code.push(Instruction::Continue),
+                ByteCode::Switch => {
+                    let Some(count) = reader.read_u32() else {
+                        return Err("Expected case count".to_string());
+                    };
+
+                    let mut cases = Vec::new();
+
+                    for _ in 0..=count {
+                        let Some(lenght) = reader.read_u32() else {
+                            return Err("Expected block code length".to_string());
+                        };
+
+                        let Some(block) = reader.read_bytes(lenght as usize) else {
+                            return Err("Expected block code".to_string());
+                        };
+
+                        cases.push(Instruction::from_bytecode_with(block, encoding)?);
+                    }
+
+                    let default = cases.pop().unwrap_or_default();
+
+                    code.push(Instruction::Switch { cases, default });
+                }
+                ByteCode::Break => code.push(Instruction::Break { depth: 0 }),
+                ByteCode::Continue => code.push(Instruction::Continue { depth: 0 }),
+                ByteCode::BreakTo => {
+                    let Some(depth) = reader.read_u32() else {
+                        return Err("Expected loop depth".to_string());
+                    };
+
+                    code.push(Instruction::Break { depth });
+                }
+                ByteCode::ContinueTo => {
+                    let Some(depth) = reader.read_u32() else {
+                        return Err("Expected loop depth".to_string());
+                    };
+
+                    code.push(Instruction::Continue { depth });
+                }
             }
         }
         Ok(code)
     }
 
+    // First byte of the encoded instruction
+    pub fn opcode(&self) -> ByteCode {
+        match self {
+            Instruction::None => ByteCode::None,
+            Instruction::Version { .. } => ByteCode::Version,
+            Instruction::Dump => ByteCode::Dump,
+            Instruction::Hi => ByteCode::Hi,
+            Instruction::Fn {
+                signature: None, ..
+            } => ByteCode::Func,
+            Instruction::Fn { .. } => ByteCode::FuncSig,
+            Instruction::Call { .. } => ByteCode::Call,
+            Instruction::TailCall { .. } => ByteCode::TailCall,
+            Instruction::MakeClosure { .. } => ByteCode::MakeClosure,
+            Instruction::GetUpvalue { .. } => ByteCode::GetUpvalue,
+            Instruction::SetUpvalue { .. } => ByteCode::SetUpvalue,
+            Instruction::CallClosure { .. } => ByteCode::CallClosure,
+            Instruction::Global { .. } => ByteCode::Global,
+            Instruction::GetGlobal { .. } => ByteCode::GetGlobal,
+            Instruction::SetGlobal { .. } => ByteCode::SetGlobal,
+            Instruction::Constants { .. } => ByteCode::Constants,
+            Instruction::GetConst { .. } => ByteCode::GetConst,
+            Instruction::PushConstString { .. } => ByteCode::PushConstString,
+            Instruction::PushConstInteger { .. } => ByteCode::PushConstInteger,
+            Instruction::PushConstFloat { .. } => ByteCode::PushConstFloat,
+            Instruction::PushConstBoolean { .. } => ByteCode::PushConstBoolean,
+            Instruction::GetLocal { .. } => ByteCode::GetLocal,
+            Instruction::SetLocal { .. } => ByteCode::SetLocal,
+            Instruction::ReserveLocal { .. } => ByteCode::ReserveLocal,
+            Instruction::Allocate { .. } => ByteCode::Allocate,
+            Instruction::Import { .. } => ByteCode::Import,
+            Instruction::Struct { .. } => ByteCode::Struct,
+            Instruction::New { .. } => ByteCode::New,
+            Instruction::IsInstance { .. } => ByteCode::IsInstance,
+            Instruction::GetField { .. } => ByteCode::GetField,
+            Instruction::SetField { .. } => ByteCode::SetField,
+            Instruction::GetFieldByName { .. } => ByteCode::GetFieldByName,
+            Instruction::SetFieldByName { .. } => ByteCode::SetFieldByName,
+            Instruction::CallMethod { .. } => ByteCode::CallMethod,
+            Instruction::Pop => ByteCode::Pop,
+            Instruction::Dup => ByteCode::Dup,
+            Instruction::Swap => ByteCode::Swap,
+            Instruction::Over => ByteCode::Over,
+            Instruction::Rot => ByteCode::Rot,
+            Instruction::Pick { .. } => ByteCode::Pick,
+            Instruction::Drop { .. } => ByteCode::Drop,
+            Instruction::DupN { .. } => ByteCode::DupN,
+            Instruction::Add => ByteCode::Add,
+            Instruction::Sub => ByteCode::Sub,
+            Instruction::Mul => ByteCode::Mul,
+            Instruction::Div => ByteCode::Div,
+            Instruction::Inc => ByteCode::Inc,
+            Instruction::Dec => ByteCode::Dec,
+            Instruction::Eq => ByteCode::Eq,
+            Instruction::Ne => ByteCode::Ne,
+            Instruction::Lt => ByteCode::Lt,
+            Instruction::Le => ByteCode::Le,
+            Instruction::Gt => ByteCode::Gt,
+            Instruction::Ge => ByteCode::Ge,
+            Instruction::Module { .. } => ByteCode::Module,
+            Instruction::LoadModule { .. } => ByteCode::LoadModule,
+            Instruction::GetFunction { .. } => ByteCode::GetFunction,
+            Instruction::Return => ByteCode::Return,
+            Instruction::Then { .. } => ByteCode::Then,
+            Instruction::Loop { .. } => ByteCode::Loop,
+            Instruction::Switch { .. } => ByteCode::Switch,
+            Instruction::Break { depth: 0 } => ByteCode::Break,
+            Instruction::Break { .. } => ByteCode::BreakTo,
+            Instruction::Continue { depth: 0 } => ByteCode::Continue,
+            Instruction::Continue { .. } => ByteCode::ContinueTo,
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with(Encoding::Fixed)
+    }
+
+    pub fn to_bytes_with(&self, encoding: Encoding) -> Vec<u8> {
         let mut bytes = Vec::new();
-        let mut writer = ByteWriter::new(&mut bytes);
+        let mut writer = ByteWriter::with_encoding(&mut bytes, encoding);
 
         match self {
             Instruction::None => writer.write_byte(ByteCode::None as u8),
@@ -578,13 +1047,41 @@ impl<'a> Instruction {
             }
             Instruction::Dump => writer.write_byte(ByteCode::Dump as u8),
             Instruction::Hi => writer.write_byte(ByteCode::Hi as u8),
-            Instruction::Fn { name, code } => {
-                writer.write_byte(ByteCode::Func as u8);
+            Instruction::Fn {
+                name,
+                signature,
+                code,
+            } => {
+                let code_bytes = Instruction::code_to_bytes_with(code, encoding);
 
-                let code_bytes = Instruction::code_to_bytes(code);
+                match signature {
+                    None => {
+                        writer.write_byte(ByteCode::Func as u8);
+                        writer.write_u32(code_bytes.len() as u32);
+                        writer.write_string(name);
+                    }
+                    Some(signature) => {
+                        writer.write_byte(ByteCode::FuncSig as u8);
+                        writer.write_u32(code_bytes.len() as u32);
+                        writer.write_string(name);
+                        writer.write_u32(signature.params);
+                        writer.write_u32(signature.results);
+                        writer.write_u32(signature.locals);
+                        writer.write_byte(signature.is_typed() as u8);
+
+                        if signature.is_typed() {
+                            for value in signature
+                                .param_types
+                                .iter()
+                                .chain(signature.result_types.iter())
+                                .chain(signature.local_types.iter())
+                            {
+                                writer.write_byte(*value as u8);
+                            }
+                        }
+                    }
+                }
 
-                writer.write_u32(code_bytes.len() as u32);
-                writer.write_string(name);
                 writer.write_bytes(&code_bytes);
             }
             Instruction::Call {
@@ -597,6 +1094,97 @@ impl<'a> Instruction {
                 writer.write_string(function);
                 writer.write_u32(*param_count);
             }
+            Instruction::TailCall {
+                module,
+                function,
+                param_count,
+            } => {
+                writer.write_byte(ByteCode::TailCall as u8);
+                writer.write_string(module);
+                writer.write_string(function);
+                writer.write_u32(*param_count);
+            }
+            Instruction::MakeClosure {
+                module,
+                function,
+                captures,
+            } => {
+                writer.write_byte(ByteCode::MakeClosure as u8);
+                writer.write_string(module);
+                writer.write_string(function);
+                writer.write_u32(captures.len() as u32);
+
+                for capture in captures.iter() {
+                    match capture {
+                        Capture::Value(index) => {
+                            writer.write_byte(0);
+                            writer.write_u32(*index);
+                        }
+                        Capture::Cell(index) => {
+                            writer.write_byte(1);
+                            writer.write_u32(*index);
+                        }
+                    }
+                }
+            }
+            Instruction::GetUpvalue { index } => {
+                writer.write_byte(ByteCode::GetUpvalue as u8);
+                writer.write_u32(*index);
+            }
+            Instruction::SetUpvalue { index } => {
+                writer.write_byte(ByteCode::SetUpvalue as u8);
+                writer.write_u32(*index);
+            }
+            Instruction::CallClosure { param_count } => {
+                writer.write_byte(ByteCode::CallClosure as u8);
+                writer.write_u32(*param_count);
+            }
+            Instruction::Global { name, init } => {
+                writer.write_byte(ByteCode::Global as u8);
+
+                let init_bytes = Instruction::code_to_bytes_with(init, encoding);
+
+                writer.write_u32(init_bytes.len() as u32);
+                writer.write_string(name);
+                writer.write_bytes(&init_bytes);
+            }
+            Instruction::GetGlobal { index } => {
+                writer.write_byte(ByteCode::GetGlobal as u8);
+                writer.write_u32(*index);
+            }
+            Instruction::SetGlobal { index } => {
+                writer.write_byte(ByteCode::SetGlobal as u8);
+                writer.write_u32(*index);
+            }
+            Instruction::Constants { values } => {
+                writer.write_byte(ByteCode::Constants as u8);
+                writer.write_u32(values.len() as u32);
+
+                for value in values.iter() {
+                    match value {
+                        Constant::String(value) => {
+                            writer.write_byte(0);
+                            writer.write_string(value);
+                        }
+                        Constant::Integer(value) => {
+                            writer.write_byte(1);
+                            writer.write_i32(*value);
+                        }
+                        Constant::Float(value) => {
+                            writer.write_byte(2);
+                            writer.write_f32(*value);
+                        }
+                        Constant::Boolean(value) => {
+                            writer.write_byte(3);
+                            writer.write_bool(*value);
+                        }
+                    }
+                }
+            }
+            Instruction::GetConst { index } => {
+                writer.write_byte(ByteCode::GetConst as u8);
+                writer.write_u32(*index);
+            }
             Instruction::PushConstString { value } => {
                 writer.write_byte(ByteCode::PushConstString as u8);
                 writer.write_string(value);
@@ -629,6 +1217,33 @@ impl<'a> Instruction {
                 writer.write_byte(ByteCode::Allocate as u8);
                 writer.write_u32(*fields);
             }
+            Instruction::Import { module, functions } => {
+                writer.write_byte(ByteCode::Import as u8);
+                writer.write_string(module);
+                writer.write_u32(functions.len() as u32);
+
+                for function in functions.iter() {
+                    writer.write_string(function);
+                }
+            }
+            Instruction::Struct { name, fields } => {
+                writer.write_byte(ByteCode::Struct as u8);
+                writer.write_string(name);
+                writer.write_u32(fields.len() as u32);
+
+                for field in fields.iter() {
+                    writer.write_string(field);
+                }
+            }
+            Instruction::New { name, fields } => {
+                writer.write_byte(ByteCode::New as u8);
+                writer.write_string(name);
+                writer.write_u32(*fields);
+            }
+            Instruction::IsInstance { name } => {
+                writer.write_byte(ByteCode::IsInstance as u8);
+                writer.write_string(name);
+            }
             Instruction::GetField { index } => {
                 writer.write_byte(ByteCode::GetField as u8);
                 writer.write_u32(*index);
@@ -637,8 +1252,36 @@ impl<'a> Instruction {
                 writer.write_byte(ByteCode::SetField as u8);
                 writer.write_u32(*index);
             }
+            Instruction::GetFieldByName { name } => {
+                writer.write_byte(ByteCode::GetFieldByName as u8);
+                writer.write_string(name);
+            }
+            Instruction::SetFieldByName { name } => {
+                writer.write_byte(ByteCode::SetFieldByName as u8);
+                writer.write_string(name);
+            }
+            Instruction::CallMethod { name, param_count } => {
+                writer.write_byte(ByteCode::CallMethod as u8);
+                writer.write_string(name);
+                writer.write_u32(*param_count);
+            }
             Instruction::Pop => writer.write_byte(ByteCode::Pop as u8),
             Instruction::Dup => writer.write_byte(ByteCode::Dup as u8),
+            Instruction::Swap => writer.write_byte(ByteCode::Swap as u8),
+            Instruction::Over => writer.write_byte(ByteCode::Over as u8),
+            Instruction::Rot => writer.write_byte(ByteCode::Rot as u8),
+            Instruction::Pick { depth } => {
+                writer.write_byte(ByteCode::Pick as u8);
+                writer.write_u32(*depth);
+            }
+            Instruction::Drop { count } => {
+                writer.write_byte(ByteCode::Drop as u8);
+                writer.write_u32(*count);
+            }
+            Instruction::DupN { count } => {
+                writer.write_byte(ByteCode::DupN as u8);
+                writer.write_u32(*count);
+            }
             Instruction::Add => writer.write_byte(ByteCode::Add as u8),
             Instruction::Sub => writer.write_byte(ByteCode::Sub as u8),
             Instruction::Mul => writer.write_byte(ByteCode::Mul as u8),
@@ -654,7 +1297,7 @@ impl<'a> Instruction {
             Instruction::Module { name, code } => {
                 writer.write_byte(ByteCode::Module as u8);
 
-                let code_bytes = Instruction::code_to_bytes(code);
+                let code_bytes = Instruction::code_to_bytes_with(code, encoding);
 
                 writer.write_u32(code_bytes.len() as u32);
                 writer.write_string(name);
@@ -663,7 +1306,7 @@ impl<'a> Instruction {
             Instruction::LoadModule { name, code } => {
                 writer.write_byte(ByteCode::LoadModule as u8);
 
-                let code_bytes = Instruction::code_to_bytes(code);
+                let code_bytes = Instruction::code_to_bytes_with(code, encoding);
 
                 writer.write_u32(code_bytes.len() as u32);
                 writer.write_string(name);
@@ -685,15 +1328,15 @@ impl<'a> Instruction {
             } => {
                 writer.write_byte(ByteCode::Then as u8);
 
-                let block_bytes = Instruction::code_to_bytes(then_block);
+                let block_bytes = Instruction::code_to_bytes_with(then_block, encoding);
 
                 writer.write_u32(block_bytes.len() as u32);
                 writer.write_bytes(&block_bytes);
 
-                if else_block.len() > 0 {
+                if !else_block.is_empty() {
                     writer.write_byte(ByteCode::Else as u8);
 
-                    let block_bytes = Instruction::code_to_bytes(else_block);
+                    let block_bytes = Instruction::code_to_bytes_with(else_block, encoding);
 
                     writer.write_u32(block_bytes.len() as u32);
                     writer.write_bytes(&block_bytes);
@@ -702,13 +1345,32 @@ impl<'a> Instruction {
             Instruction::Loop { block } => {
                 writer.write_byte(ByteCode::Loop as u8);
 
-                let block_bytes = Instruction::code_to_bytes(block);
+                let block_bytes = Instruction::code_to_bytes_with(block, encoding);
 
                 writer.write_u32(block_bytes.len() as u32);
                 writer.write_bytes(&block_bytes);
             }
-            Instruction::Break => writer.write_byte(ByteCode::Break as u8),
-            Instruction::Continue => writer.write_byte(ByteCode::Continue as u8),
+            Instruction::Switch { cases, default } => {
+                writer.write_byte(ByteCode::Switch as u8);
+                writer.write_u32(cases.len() as u32);
+
+                for block in cases.iter().chain(std::iter::once(default)) {
+                    let block_bytes = Instruction::code_to_bytes_with(block, encoding);
+
+                    writer.write_u32(block_bytes.len() as u32);
+                    writer.write_bytes(&block_bytes);
+                }
+            }
+            Instruction::Break { depth: 0 } => writer.write_byte(ByteCode::Break as u8),
+            Instruction::Break { depth } => {
+                writer.write_byte(ByteCode::BreakTo as u8);
+                writer.write_u32(*depth);
+            }
+            Instruction::Continue { depth: 0 } => writer.write_byte(ByteCode::Continue as u8),
+            Instruction::Continue { depth } => {
+                writer.write_byte(ByteCode::ContinueTo as u8);
+                writer.write_u32(*depth);
+            }
         }
 
         bytes
@@ -716,11 +1378,15 @@ impl<'a> Instruction {
 
     // Convert a vector of instructions to a vector of bytes
     pub fn code_to_bytes(code: &Code) -> Vec<u8> {
+        Instruction::code_to_bytes_with(code, Encoding::Fixed)
+    }
+
+    pub fn code_to_bytes_with(code: &Code, encoding: Encoding) -> Vec<u8> {
         let mut bytes = Vec::new();
         let mut writer = ByteWriter::new(&mut bytes);
 
         for instruction in code.iter() {
-            writer.write_bytes(&instruction.to_bytes());
+            writer.write_bytes(&instruction.to_bytes_with(encoding));
         }
 
         bytes
@@ -728,6 +1394,11 @@ impl<'a> Instruction {
 
     // Convert a S-expression to an instruction
     pub fn from_sexpr(sexpr: &SExpr) -> Result<Instruction, String> {
+        Instruction::from_sexpr_in(sexpr, &mut Scope::new())
+    }
+
+    // Convert a S-expression to an instruction resolving names declared in the scope
+    pub(crate) fn from_sexpr_in(sexpr: &SExpr, scope: &mut Scope) -> Result<Instruction, String> {
         match sexpr {
             SExpr::Atom(value) => Err(format!("Unexpected atom: {}", value)),
             SExpr::List(values) => {
@@ -752,9 +1423,9 @@ impl<'a> Instruction {
                         let patch = version.next().unwrap().parse::<u8>().unwrap();
 
                         Ok(Instruction::Version {
-                            major: major,
-                            minor: minor,
-                            patch: patch,
+                            major,
+                            minor,
+                            patch,
                         })
                     }
                     "dump" => Ok(Instruction::Dump),
@@ -765,15 +1436,73 @@ impl<'a> Instruction {
                             _ => return Err("Expected function name".to_string()),
                         };
 
+                        let mut it = it.peekable();
+                        let mut signature = None;
+
+                        // Optional (params N) (results M) (locals K) headers, counts can be
+                        // replaced by type names like (params i32 f32)
+                        while let Some(SExpr::List(header)) = it.peek() {
+                            let slot = match header.first() {
+                                Some(SExpr::Atom(head)) if head == "params" => 0,
+                                Some(SExpr::Atom(head)) if head == "results" => 1,
+                                Some(SExpr::Atom(head)) if head == "locals" => 2,
+                                _ => break,
+                            };
+
+                            let mut types = Vec::new();
+
+                            let count = match &header[1..] {
+                                [SExpr::Atom(value)] if value.parse::<u32>().is_ok() => {
+                                    value.parse::<u32>().unwrap()
+                                }
+                                values => {
+                                    for value in values {
+                                        let SExpr::Atom(value) = value else {
+                                            return Err("Expected type name".to_string());
+                                        };
+
+                                        types.push(
+                                            Type::from_name(value)
+                                                .ok_or(format!("Unknown type: {}", value))?,
+                                        );
+                                    }
+
+                                    types.len() as u32
+                                }
+                            };
+
+                            let signature = signature.get_or_insert(Signature::default());
+
+                            match slot {
+                                0 => (signature.params, signature.param_types) = (count, types),
+                                1 => (signature.results, signature.result_types) = (count, types),
+                                _ => (signature.locals, signature.local_types) = (count, types),
+                            }
+
+                            it.next();
+                        }
+
+                        // Headers given as counts are untyped in a typed signature
+                        if let Some(signature) = signature.as_mut().filter(|s| s.is_typed()) {
+                            for (types, count) in [
+                                (&mut signature.param_types, signature.params),
+                                (&mut signature.result_types, signature.results),
+                                (&mut signature.local_types, signature.locals),
+                            ] {
+                                types.resize(count as usize, Type::Any);
+                            }
+                        }
+
                         let mut code = Vec::new();
 
-                        while let Some(value) = it.next() {
-                            let instruction = Instruction::from_sexpr(value)?;
+                        for value in it {
+                            let instruction = Instruction::from_sexpr_in(value, scope)?;
                             code.push(instruction);
                         }
 
                         Ok(Instruction::Fn {
                             name: name.to_string(),
+                            signature,
                             code,
                         })
                     }
@@ -799,15 +1528,147 @@ impl<'a> Instruction {
                             param_count,
                         })
                     }
+                    "call.tail" => {
+                        let module = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected module name".to_string()),
+                        };
+
+                        let function = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected function name".to_string()),
+                        };
+
+                        let param_count = match it.next() {
+                            Some(SExpr::Atom(value)) => value.parse::<u32>().unwrap(),
+                            _ => return Err("Expected parameter count".to_string()),
+                        };
+
+                        Ok(Instruction::TailCall {
+                            module: module.to_string(),
+                            function: function.to_string(),
+                            param_count,
+                        })
+                    }
+                    "closure" => {
+                        let module = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected module name".to_string()),
+                        };
+
+                        let function = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected function name".to_string()),
+                        };
+
+                        let mut captures = Vec::new();
+
+                        for value in it {
+                            let SExpr::List(capture) = value else {
+                                return Err("Expected (val <index>) or (cell <index>)".to_string());
+                            };
+
+                            let index = match capture.get(1) {
+                                Some(SExpr::Atom(value)) => value.parse::<u32>().unwrap(),
+                                _ => return Err("Expected local index".to_string()),
+                            };
+
+                            captures.push(match capture.first() {
+                                Some(SExpr::Atom(kind)) if kind == "val" => Capture::Value(index),
+                                Some(SExpr::Atom(kind)) if kind == "cell" => Capture::Cell(index),
+                                _ => {
+                                    return Err(
+                                        "Expected (val <index>) or (cell <index>)".to_string()
+                                    )
+                                }
+                            });
+                        }
+
+                        Ok(Instruction::MakeClosure {
+                            module: module.to_string(),
+                            function: function.to_string(),
+                            captures,
+                        })
+                    }
+                    "upval.get" => {
+                        let index = match it.next() {
+                            Some(SExpr::Atom(value)) => value.parse::<u32>().unwrap(),
+                            _ => return Err("Expected upvalue index".to_string()),
+                        };
+
+                        Ok(Instruction::GetUpvalue { index })
+                    }
+                    "upval.set" => {
+                        let index = match it.next() {
+                            Some(SExpr::Atom(value)) => value.parse::<u32>().unwrap(),
+                            _ => return Err("Expected upvalue index".to_string()),
+                        };
+
+                        Ok(Instruction::SetUpvalue { index })
+                    }
+                    "call.closure" => {
+                        let param_count = match it.next() {
+                            Some(SExpr::Atom(value)) => value.parse::<u32>().unwrap(),
+                            _ => return Err("Expected parameter count".to_string()),
+                        };
+
+                        Ok(Instruction::CallClosure { param_count })
+                    }
+                    "global" => {
+                        let name = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected global name".to_string()),
+                        };
+
+                        let mut init = Vec::new();
+
+                        for value in it {
+                            let instruction = Instruction::from_sexpr_in(value, scope)?;
+                            init.push(instruction);
+                        }
+
+                        Ok(Instruction::Global {
+                            name: name.to_string(),
+                            init,
+                        })
+                    }
+                    "global.get" => {
+                        let index = match it.next() {
+                            Some(SExpr::Atom(value)) => scope.resolve_global(value)?,
+                            _ => return Err("Expected global index".to_string()),
+                        };
+
+                        Ok(Instruction::GetGlobal { index })
+                    }
+                    "global.set" => {
+                        let index = match it.next() {
+                            Some(SExpr::Atom(value)) => scope.resolve_global(value)?,
+                            _ => return Err("Expected global index".to_string()),
+                        };
+
+                        Ok(Instruction::SetGlobal { index })
+                    }
                     "str.const" => {
                         let value = match it.next() {
                             Some(SExpr::Atom(value)) => value,
                             _ => return Err("Expected string value".to_string()),
                         };
 
-                        Ok(Instruction::PushConstString {
-                            value: value.to_string(),
-                        })
+                        // Inside a module string literals are stored once in the constant pool
+                        match scope.intern_constant(Constant::String(value.to_string())) {
+                            Some(index) => Ok(Instruction::GetConst { index }),
+                            None => Ok(Instruction::PushConstString {
+                                value: value.to_string(),
+                            }),
+                        }
+                    }
+                    "const.get" => {
+                        let index = match it.next() {
+                            Some(SExpr::Atom(value)) => scope.resolve_constant(value)?,
+                            _ => return Err("Expected constant index".to_string()),
+                        };
+
+                        Ok(Instruction::GetConst { index })
                     }
                     "i32.const" => {
                         let value = match it.next() {
@@ -865,24 +1726,115 @@ impl<'a> Instruction {
 
                         Ok(Instruction::Allocate { fields })
                     }
-                    "field.get" => {
-                        let index = match it.next() {
-                            Some(SExpr::Atom(value)) => value.parse::<u32>().unwrap(),
-                            _ => return Err("Expected field index".to_string()),
+                    "struct" => {
+                        let name = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected struct name".to_string()),
                         };
 
-                        Ok(Instruction::GetField { index })
+                        let mut fields = Vec::new();
+
+                        for value in it {
+                            match value {
+                                SExpr::Atom(field) => fields.push(field.to_string()),
+                                _ => return Err("Expected field name".to_string()),
+                            }
+                        }
+
+                        Ok(Instruction::Struct {
+                            name: name.to_string(),
+                            fields,
+                        })
                     }
-                    "field.set" => {
-                        let index = match it.next() {
+                    "new" => {
+                        let name = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected struct name".to_string()),
+                        };
+
+                        Ok(Instruction::New {
+                            name: name.to_string(),
+                            fields: scope.resolve_struct(name)?.len() as u32,
+                        })
+                    }
+                    "is" => {
+                        let name = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected struct name".to_string()),
+                        };
+
+                        scope.resolve_struct(name)?;
+
+                        Ok(Instruction::IsInstance {
+                            name: name.to_string(),
+                        })
+                    }
+                    "field.get" => match it.next() {
+                        // A bare name is looked up on the object when the instruction runs
+                        Some(SExpr::Atom(value)) if Scope::is_field_name(value) => {
+                            Ok(Instruction::GetFieldByName {
+                                name: value.to_string(),
+                            })
+                        }
+                        Some(SExpr::Atom(value)) => Ok(Instruction::GetField {
+                            index: scope.resolve_field(value)?,
+                        }),
+                        _ => Err("Expected field index".to_string()),
+                    },
+                    "field.set" => match it.next() {
+                        Some(SExpr::Atom(value)) if Scope::is_field_name(value) => {
+                            Ok(Instruction::SetFieldByName {
+                                name: value.to_string(),
+                            })
+                        }
+                        Some(SExpr::Atom(value)) => Ok(Instruction::SetField {
+                            index: scope.resolve_field(value)?,
+                        }),
+                        _ => Err("Expected field index".to_string()),
+                    },
+                    "call.method" => {
+                        let name = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected method name".to_string()),
+                        };
+
+                        let param_count = match it.next() {
                             Some(SExpr::Atom(value)) => value.parse::<u32>().unwrap(),
-                            _ => return Err("Expected field index".to_string()),
+                            _ => return Err("Expected parameter count".to_string()),
                         };
 
-                        Ok(Instruction::SetField { index })
+                        Ok(Instruction::CallMethod {
+                            name: name.to_string(),
+                            param_count,
+                        })
                     }
                     "pop" => Ok(Instruction::Pop),
-                    "dup" => Ok(Instruction::Dup),
+                    "dup" => match it.next() {
+                        Some(SExpr::Atom(value)) => Ok(Instruction::DupN {
+                            count: value.parse::<u32>().unwrap(),
+                        }),
+                        None => Ok(Instruction::Dup),
+                        _ => Err("Expected element count".to_string()),
+                    },
+                    "swap" => Ok(Instruction::Swap),
+                    "over" => Ok(Instruction::Over),
+                    "rot" => Ok(Instruction::Rot),
+                    "pick" => {
+                        let depth = match it.next() {
+                            Some(SExpr::Atom(value)) => value.parse::<u32>().unwrap(),
+                            _ => return Err("Expected stack depth".to_string()),
+                        };
+
+                        Ok(Instruction::Pick { depth })
+                    }
+                    "drop" => {
+                        let count = match it.next() {
+                            Some(SExpr::Atom(value)) => value.parse::<u32>().unwrap(),
+                            _ => return Err("Expected element count".to_string()),
+                        };
+
+                        Ok(Instruction::Drop { count })
+                    }
                     "op.add" => Ok(Instruction::Add),
                     "op.sub" => Ok(Instruction::Sub),
                     "op.mul" => Ok(Instruction::Mul),
@@ -901,18 +1853,77 @@ impl<'a> Instruction {
                             _ => return Err("Expected module name".to_string()),
                         };
 
+                        let body: Vec<&SExpr> = it.collect();
+
+                        // Struct and global declarations are visible to the whole module
+                        let mut scope = Scope::module();
+
+                        for value in body.iter() {
+                            let SExpr::List(values) = value else {
+                                continue;
+                            };
+
+                            match values.first() {
+                                Some(SExpr::Atom(head)) if head == "struct" => {
+                                    if let Instruction::Struct { name, fields } =
+                                        Instruction::from_sexpr_in(value, &mut scope)?
+                                    {
+                                        if scope.structs.insert(name.clone(), fields).is_some() {
+                                            return Err(format!("Duplicate struct: {}", name));
+                                        }
+                                    }
+                                }
+                                Some(SExpr::Atom(head)) if head == "global" => {
+                                    let Some(SExpr::Atom(name)) = values.get(1) else {
+                                        return Err("Expected global name".to_string());
+                                    };
+
+                                    if scope.globals.contains(name) {
+                                        return Err(format!("Duplicate global: {}", name));
+                                    }
+
+                                    scope.globals.push(name.clone());
+                                }
+                                _ => {}
+                            }
+                        }
+
                         let mut module_code = Vec::new();
 
-                        while let Some(value) = it.next() {
-                            let instruction = Instruction::from_sexpr(value)?;
+                        for value in body {
+                            let instruction = Instruction::from_sexpr_in(value, &mut scope)?;
                             module_code.push(instruction);
                         }
 
+                        if let Some(constants) = scope.constants.filter(|c| !c.is_empty()) {
+                            module_code.insert(0, Instruction::Constants { values: constants });
+                        }
+
                         Ok(Instruction::Module {
                             name: name.to_string(),
                             code: module_code,
                         })
                     }
+                    "import" => {
+                        let module = match it.next() {
+                            Some(SExpr::Atom(value)) => value,
+                            _ => return Err("Expected module name".to_string()),
+                        };
+
+                        let mut functions = Vec::new();
+
+                        for value in it {
+                            match value {
+                                SExpr::Atom(function) => functions.push(function.to_string()),
+                                _ => return Err("Expected function name".to_string()),
+                            }
+                        }
+
+                        Ok(Instruction::Import {
+                            module: module.to_string(),
+                            functions,
+                        })
+                    }
                     "mod.load" => {
                         let name = match it.next() {
                             Some(SExpr::Atom(value)) => value,
@@ -921,8 +1932,8 @@ impl<'a> Instruction {
 
                         let mut module_code = Vec::new();
 
-                        while let Some(value) = it.next() {
-                            let instruction = Instruction::from_sexpr(value)?;
+                        for value in it.by_ref() {
+                            let instruction = Instruction::from_sexpr_in(value, scope)?;
                             module_code.push(instruction);
                         }
 
@@ -960,7 +1971,7 @@ impl<'a> Instruction {
 
                         let mut has_else = false;
 
-                        while let Some(value) = it.next() {
+                        for value in it.by_ref() {
                             match value {
                                 SExpr::Atom(value) => {
                                     if value == "else" {
@@ -971,17 +1982,17 @@ impl<'a> Instruction {
                                     }
                                 }
                                 SExpr::List(_) => {
-                                    let instruction = Instruction::from_sexpr(value)?;
+                                    let instruction = Instruction::from_sexpr_in(value, scope)?;
                                     then_block.push(instruction);
                                 }
                             }
                         }
 
                         if has_else {
-                            while let Some(value) = it.next() {
+                            for value in it.by_ref() {
                                 match value {
                                     SExpr::List(_) => {
-                                        let instruction = Instruction::from_sexpr(value)?;
+                                        let instruction = Instruction::from_sexpr_in(value, scope)?;
                                         else_block.push(instruction);
                                     }
                                     _ => return Err("Unexpected atom".to_string()),
@@ -991,26 +2002,95 @@ impl<'a> Instruction {
 
                         Ok(Instruction::Then {
                             then_block,
-                            else_block: else_block,
+                            else_block,
                         })
                     }
                     "loop" => {
+                        let mut it = it.peekable();
+                        let mut label = None;
+
+                        if let Some(SExpr::Atom(value)) = it.peek() {
+                            if value.starts_with('$') {
+                                label = Some(value.to_string());
+                                it.next();
+                            }
+                        }
+
                         let mut block = Vec::new();
 
-                        while let Some(value) = it.next() {
-                            match value {
-                                SExpr::List(_) => {
-                                    let instruction = Instruction::from_sexpr(value)?;
-                                    block.push(instruction);
+                        scope.labels.push(label);
+
+                        // The label is popped before any error is returned
+                        let result = it.try_for_each(|value| match value {
+                            SExpr::List(_) => {
+                                block.push(Instruction::from_sexpr_in(value, scope)?);
+                                Ok(())
+                            }
+                            _ => Err("Unexpected atom".to_string()),
+                        });
+
+                        scope.labels.pop();
+                        result?;
+
+                        Ok(Instruction::Loop { block })
+                    }
+                    "switch" => {
+                        let mut cases = Vec::new();
+                        let mut default = None;
+
+                        for value in it {
+                            let SExpr::List(values) = value else {
+                                return Err("Unexpected atom".to_string());
+                            };
+
+                            let mut block = Vec::new();
+
+                            for value in values.iter().skip(1) {
+                                block.push(Instruction::from_sexpr_in(value, scope)?);
+                            }
+
+                            match values.first() {
+                                Some(SExpr::Atom(kind)) if kind == "case" => {
+                                    if default.is_some() {
+                                        return Err("Unexpected (case) after (default)".to_string());
+                                    }
+
+                                    cases.push(block);
+                                }
+                                Some(SExpr::Atom(kind)) if kind == "default" => {
+                                    if default.is_some() {
+                                        return Err("Duplicate (default) block".to_string());
+                                    }
+
+                                    default = Some(block);
                                 }
-                                _ => return Err("Unexpected atom".to_string()),
+                                _ => return Err("Expected (case ...) or (default ...)".to_string()),
                             }
                         }
 
-                        Ok(Instruction::Loop { block: block })
+                        Ok(Instruction::Switch {
+                            cases,
+                            default: default.unwrap_or_default(),
+                        })
+                    }
+                    "break" => {
+                        let depth = match it.next() {
+                            Some(SExpr::Atom(value)) => scope.resolve_label(value)?,
+                            None => scope.resolve_depth(0)?,
+                            _ => return Err("Expected loop label or depth".to_string()),
+                        };
+
+                        Ok(Instruction::Break { depth })
+                    }
+                    "continue" => {
+                        let depth = match it.next() {
+                            Some(SExpr::Atom(value)) => scope.resolve_label(value)?,
+                            None => scope.resolve_depth(0)?,
+                            _ => return Err("Expected loop label or depth".to_string()),
+                        };
+
+                        Ok(Instruction::Continue { depth })
                     }
-                    "break" => Ok(Instruction::Break),
-                    "continue" => Ok(Instruction::Continue),
                     _ => Err(format!("Unknown instruction: {}", name)),
                 }
             }
@@ -1018,7 +2098,7 @@ impl<'a> Instruction {
     }
 
     // Convert a vector of S-expressions to a vector of instructions
-    pub fn from_sexprs(sexprs: &Vec<SExpr>) -> Result<Code, String> {
+    pub fn from_sexprs(sexprs: &[SExpr]) -> Result<Code, String> {
         let mut code = Vec::new();
 
         for sexpr in sexprs.iter() {
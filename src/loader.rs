@@ -0,0 +1,134 @@
+use crate::{type_check, verify, Code, DyModule, Instruction, Module, Policy, SearchPath, Version};
+
+// Turns assembled or decoded code into modules ready to be added to a virtual machine
+#[derive(Debug, Clone)]
+pub struct Loader {
+    pub policy: Policy,
+    pub search_path: SearchPath,
+    pub verify: bool, // Reject code the verifier finds problems in
+}
+
+impl Default for Loader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Loader {
+    pub fn new() -> Loader {
+        Loader {
+            policy: Policy::allow_all(),
+            search_path: SearchPath::from_env(),
+            verify: false,
+        }
+    }
+
+    pub fn search_path(mut self, search_path: SearchPath) -> Loader {
+        self.search_path = search_path;
+        self
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Loader {
+        self.policy = policy;
+        self
+    }
+
+    pub fn verify(mut self, verify: bool) -> Loader {
+        self.verify = verify;
+        self
+    }
+
+    pub fn load(&self, code: &Code) -> Result<(Vec<Module>, Vec<DyModule>), String> {
+        // validate version
+        Version::of(code)?.check_compatible(&Version::current())?;
+
+        let mut checked = Vec::new();
+
+        if self.verify {
+            let mut diagnostics = verify(code);
+            let report = type_check(code);
+            diagnostics.extend(report.diagnostics);
+            checked = report.checked;
+
+            if !diagnostics.is_empty() {
+                let diagnostics: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+                return Err(format!(
+                    "Verification failed:\n  {}",
+                    diagnostics.join("\n  ")
+                ));
+            }
+        }
+
+        let code = &code[1..];
+
+        // load modules
+        let mut modules = vec![];
+        let mut dy_modules = vec![];
+
+        for instruction in code.iter() {
+            match instruction {
+                Instruction::Module { name: _, code: _ } => {
+                    let mut module =
+                        Module::try_from(instruction.clone()).map_err(|e| e.to_string())?;
+
+                    for (name, function) in checked.iter() {
+                        if *name != module.name {
+                            continue;
+                        }
+
+                        if let Some(function) = module.get_function_mut(function) {
+                            function.verified = true;
+                        }
+                    }
+
+                    modules.push(module);
+                }
+                Instruction::LoadModule { name, code } => {
+                    let (path, not_found) = match self.search_path.resolve(name) {
+                        Ok(path) => (path, None),
+                        Err(error) => (SearchPath::platform_name(name), Some(error)),
+                    };
+
+                    let path = self.policy.check_library(&path)?;
+
+                    let mut dymodule =
+                        DyModule::load_from(name, &path).map_err(|error| match &not_found {
+                            Some(not_found) => format!("{}, {}", not_found, error),
+                            None => error,
+                        })?;
+                    let mut names = Vec::new();
+
+                    for instruction in code.iter() {
+                        match instruction {
+                            Instruction::GetFunction { name, alias } => {
+                                names.push((name.clone(), alias.clone()));
+                            }
+                            _ => {
+                                return Err(
+                                    "Invalid instruction type, expected (fn.get)".to_string()
+                                );
+                            }
+                        }
+                    }
+
+                    // Plugins with a manifest export everything unless (fn.get) narrows it,
+                    // older plugins need every symbol listed
+                    if !dymodule.import_manifest(&names)? {
+                        for (name, alias) in names.iter() {
+                            dymodule.load_function(name, alias.as_deref())?;
+                        }
+                    }
+
+                    dy_modules.push(dymodule);
+                }
+                _ => {
+                    return Err(
+                        "Invalid instruction type, expected (mod) or (mod.load)".to_string()
+                    );
+                }
+            }
+        }
+
+        Ok((modules, dy_modules))
+    }
+}
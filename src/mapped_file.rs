@@ -0,0 +1,202 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    ops::Deref,
+    path::Path,
+};
+
+use memmap2::Mmap;
+
+use crate::{read_code, ByteCode, Code, Container, Instruction, CONTAINER_MAGIC};
+
+// Read only view of a whole file, either read into memory or memory mapped so decoding borrows
+// the page cache instead of copying the file into the heap
+pub struct MappedFile {
+    data: Mapping,
+}
+
+enum Mapping {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl MappedFile {
+    // Read the whole file into memory
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MappedFile, String> {
+        let path = path.as_ref();
+        let mut bytes = Vec::new();
+
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|e| format!("Failed to read \"{}\": {}", path.display(), e))?;
+
+        Ok(MappedFile {
+            data: Mapping::Owned(bytes),
+        })
+    }
+
+    /// Memory map the file, falling back to reading it when it can't be mapped (empty files,
+    /// pipes, special files)
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while the mapping is alive, the bytes are
+    /// read straight from the page cache and a change is undefined behavior for every slice
+    /// borrowed from it, including decoded strings still being copied out.
+    pub unsafe fn map<P: AsRef<Path>>(path: P) -> Result<MappedFile, String> {
+        let path = path.as_ref();
+        let error = |e: io::Error| format!("Failed to read \"{}\": {}", path.display(), e);
+
+        let file = File::open(path).map_err(error)?;
+
+        if file.metadata().map_err(error)?.len() > 0 {
+            if let Ok(mapping) = Mmap::map(&file) {
+                return Ok(MappedFile {
+                    data: Mapping::Mapped(mapping),
+                });
+            }
+        }
+
+        MappedFile::open(path)
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.data, Mapping::Mapped(_))
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.data {
+            Mapping::Mapped(mapping) => mapping,
+            Mapping::Owned(bytes) => bytes,
+        }
+    }
+}
+
+// Decode a bytecode file, bare or in a container
+pub fn load_code<P: AsRef<Path>>(path: P) -> Result<Code, String> {
+    read_code(&MappedFile::open(path)?)
+}
+
+// Decode bytecode from a stream. Bare bytecode is decoded one top level instruction at a time,
+// so only the largest module is held in memory; containers are checksummed as a whole and are
+// read completely first
+pub fn read_code_from<R: Read>(mut reader: R) -> Result<Code, String> {
+    let mut magic = Vec::new();
+    read(&mut reader, &mut magic, CONTAINER_MAGIC.len())?;
+
+    if Container::is_container(&magic) {
+        reader
+            .read_to_end(&mut magic)
+            .map_err(|e| format!("Failed to read bytecode: {}", e))?;
+        return read_code(&magic);
+    }
+
+    let mut reader = magic.as_slice().chain(reader);
+    let mut code = Vec::new();
+    let mut item = Vec::new();
+
+    loop {
+        item.clear();
+
+        if read(&mut reader, &mut item, 1)? == 0 {
+            break;
+        }
+
+        match ByteCode::from_u8(item[0]) {
+            Some(ByteCode::Version) => {
+                read(&mut reader, &mut item, 3)?;
+            }
+            Some(ByteCode::Module | ByteCode::LoadModule) => {
+                // Code length and name length come first, truncated items fail to decode below
+                if read(&mut reader, &mut item, 8)? == 8 {
+                    let length = u32::from_be_bytes([item[1], item[2], item[3], item[4]]);
+                    let name = u32::from_be_bytes([item[5], item[6], item[7], item[8]]);
+                    read(&mut reader, &mut item, name as usize + length as usize)?;
+                }
+            }
+            // Anything else belongs inside a module, the rest of the stream is decoded at once
+            _ => {
+                reader
+                    .read_to_end(&mut item)
+                    .map_err(|e| format!("Failed to read bytecode: {}", e))?;
+            }
+        }
+
+        code.extend(Instruction::from_bytecode(&item)?);
+    }
+
+    Ok(code)
+}
+
+// Append up to count bytes of the stream, fewer only at its end
+fn read<R: Read>(reader: &mut R, bytes: &mut Vec<u8>, count: usize) -> Result<usize, String> {
+    reader
+        .take(count as u64)
+        .read_to_end(bytes)
+        .map_err(|e| format!("Failed to read bytecode: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn load_code_from_mapped_file() {
+        let code = assemble("(mod main (fn run (i32.const 1)))").unwrap();
+        let bytes = Container::from_code(&code).to_bytes();
+        let path = std::env::temp_dir().join(format!("ms_mapped_{}.msbc", std::process::id()));
+
+        std::fs::write(&path, &bytes).unwrap();
+
+        // Nothing else writes the file while it is mapped
+        let file = unsafe { MappedFile::map(&path) }.unwrap();
+
+        assert_eq!(&file[..], &bytes[..]);
+        assert!(file.is_mapped());
+        assert!(!MappedFile::open(&path).unwrap().is_mapped());
+
+        // Instructions compare by kind only, compare their encoding instead
+        let encoded = Instruction::code_to_bytes(&code);
+        assert_eq!(
+            Instruction::code_to_bytes(&read_code(&file).unwrap()),
+            encoded
+        );
+        assert_eq!(
+            Instruction::code_to_bytes(&load_code(&path).unwrap()),
+            encoded
+        );
+
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_code_from_streams() {
+        let code = assemble(
+            r#"
+            (mod main (fn run (i32.const 1)))
+            (mod other (global g (str.const "g")) (fn get (global.get g)))
+            "#,
+        )
+        .unwrap();
+        let encoded = Instruction::code_to_bytes(&code);
+
+        let container = Container::from_code(&code).to_bytes();
+
+        assert_eq!(
+            Instruction::code_to_bytes(&read_code_from(&encoded[..]).unwrap()),
+            encoded
+        );
+        assert_eq!(
+            Instruction::code_to_bytes(&read_code_from(&container[..]).unwrap()),
+            encoded
+        );
+        assert!(read_code_from(&encoded[..encoded.len() - 1]).is_err());
+        assert!(read_code_from(&[][..]).unwrap().is_empty());
+    }
+}
@@ -1,11 +1,21 @@
 use std::collections::HashMap;
 
-use crate::instruction::{Code, Instruction};
-use crate::Function;
+use crate::instruction::{Code, Constant, Instruction};
+use crate::{Function, Signature, Value, Version};
+
+pub struct Global {
+    pub name: String,
+    pub init: Code,   // Code evaluated when the module is added to the virtual machine
+    pub value: Value, // Current value
+}
 
 pub struct Module {
     pub name: String,
     pub functions: HashMap<String, Box<Function>>,
+    pub structs: HashMap<String, Vec<String>>, // Struct types by name with their field names
+    pub globals: Vec<Global>,                  // Globals in declaration order
+    pub constants: Vec<Constant>,              // Constant pool referenced by (const.get)
+    pub imports: HashMap<String, Vec<String>>, // Functions used from other modules
 }
 
 impl TryFrom<Instruction> for Module {
@@ -20,11 +30,37 @@ impl TryFrom<Instruction> for Module {
 
                 for instruction in code.iter() {
                     match instruction {
-                        Instruction::Fn { name, code } => {
-                            module.add_function(name.to_string(), code);
+                        Instruction::Fn {
+                            name,
+                            signature,
+                            code,
+                        } => {
+                            module.add_function(name.to_string(), signature.clone(), code);
+                        }
+                        Instruction::Struct { name, fields } => {
+                            module.structs.insert(name.clone(), fields.clone());
+                        }
+                        Instruction::Global { name, init } => {
+                            module.add_global(name.to_string(), init);
+                        }
+                        Instruction::Constants { values } => {
+                            module.constants.extend(values.iter().cloned());
+                        }
+                        Instruction::Import {
+                            module: name,
+                            functions,
+                        } => {
+                            module
+                                .imports
+                                .entry(name.clone())
+                                .or_default()
+                                .extend(functions.iter().cloned());
                         }
                         _ => {
-                            return Err("Invalid instruction type, expected (fn)".to_string());
+                            return Err(
+                                "Invalid instruction type, expected (fn), (struct), (global) or (import)"
+                                    .to_string(),
+                            );
                         }
                     }
                 }
@@ -43,15 +79,37 @@ impl Module {
         Module {
             name: name.to_string(),
             functions: HashMap::new(),
+            structs: HashMap::new(),
+            globals: Vec::new(),
+            constants: Vec::new(),
+            imports: HashMap::new(),
         }
     }
 
-    pub fn add_function(&mut self, name: String, code: &Code) {
+    pub fn add_global(&mut self, name: String, init: &Code) {
+        self.globals.push(Global {
+            name,
+            init: init.clone(),
+            value: Value::Null,
+        });
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<&Global> {
+        self.globals.iter().find(|g| g.name == name)
+    }
+
+    pub fn get_global_mut(&mut self, name: &str) -> Option<&mut Global> {
+        self.globals.iter_mut().find(|g| g.name == name)
+    }
+
+    pub fn add_function(&mut self, name: String, signature: Option<Signature>, code: &Code) {
         self.functions.insert(
             name.to_string(),
             Box::new(Function {
-                name: name,
+                name,
+                signature,
                 code: code.clone(),
+                verified: false,
             }),
         );
     }
@@ -63,4 +121,142 @@ impl Module {
     pub fn get_function_mut(&mut self, name: &str) -> Option<&mut Function> {
         self.functions.get_mut(name).map(|f| &mut **f)
     }
+
+    // Canonical (mod) instruction: imports, structs and functions sorted by name, globals in
+    // declaration order since their indices depend on it. Global values are runtime state
+    // and aren't kept.
+    pub fn to_instruction(&self) -> Instruction {
+        let mut code = Vec::new();
+
+        let mut imports: Vec<_> = self.imports.iter().collect();
+        imports.sort_by(|a, b| a.0.cmp(b.0));
+
+        for (module, functions) in imports {
+            let mut functions = functions.clone();
+            functions.sort();
+            functions.dedup();
+
+            code.push(Instruction::Import {
+                module: module.clone(),
+                functions,
+            });
+        }
+
+        if !self.constants.is_empty() {
+            code.push(Instruction::Constants {
+                values: self.constants.clone(),
+            });
+        }
+
+        let mut structs: Vec<_> = self.structs.iter().collect();
+        structs.sort_by(|a, b| a.0.cmp(b.0));
+
+        for (name, fields) in structs {
+            code.push(Instruction::Struct {
+                name: name.clone(),
+                fields: fields.clone(),
+            });
+        }
+
+        for global in self.globals.iter() {
+            code.push(Instruction::Global {
+                name: global.name.clone(),
+                init: global.init.clone(),
+            });
+        }
+
+        let mut functions: Vec<_> = self.functions.values().collect();
+        functions.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for function in functions {
+            code.push(Instruction::Fn {
+                name: function.name.clone(),
+                signature: function.signature.clone(),
+                code: function.code.clone(),
+            });
+        }
+
+        Instruction::Module {
+            name: self.name.clone(),
+            code,
+        }
+    }
+
+    // A single (mod) instruction, a loadable program needs (version) first, see program_to_bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_instruction().to_bytes()
+    }
+
+    // Bytecode of a whole program, the version of this runtime followed by the modules
+    pub fn program_to_bytes(modules: &[Module]) -> Vec<u8> {
+        let version = Version::current();
+        let mut code = vec![Instruction::Version {
+            major: version.major,
+            minor: version.minor,
+            patch: version.patch,
+        }];
+        code.extend(modules.iter().map(Module::to_instruction));
+
+        Instruction::code_to_bytes(&code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, load_modules};
+
+    #[test]
+    fn module_to_bytes_is_canonical() {
+        let source = |order: &str| {
+            format!(
+                "(mod main (import std.io print) (struct point x y) (global a (i32.const 1)) (global b (i32.const 2)) {})",
+                order
+            )
+        };
+
+        let load = |source: &str| {
+            let code = assemble(source).unwrap();
+            Module::try_from(code[1].clone()).unwrap()
+        };
+
+        let module = load(&source("(fn b (i32.const 2)) (fn a (i32.const 1)) (fn c)"));
+        let reordered = load(&source("(fn c) (fn a (i32.const 1)) (fn b (i32.const 2))"));
+        let bytes = module.to_bytes();
+
+        assert_eq!(bytes, reordered.to_bytes());
+
+        let Instruction::Module { code, .. } = module.to_instruction() else {
+            panic!("Expected (mod)");
+        };
+        let names: Vec<_> = code
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Fn { name, .. } | Instruction::Global { name, .. } => Some(name),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(names, ["a", "b", "a", "b", "c"]);
+
+        let decoded = Instruction::from_bytecode(&bytes).unwrap();
+        let module = Module::try_from(decoded[0].clone()).unwrap();
+
+        assert_eq!(module.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn program_to_bytes_loads_back() {
+        let code =
+            assemble("(mod a (fn run (call b get 0))) (mod b (fn get (i32.const 7)))").unwrap();
+        let (modules, _) = load_modules(&code).unwrap();
+
+        let bytes = Module::program_to_bytes(&modules);
+        let (loaded, _) = load_modules(&Instruction::from_bytecode(&bytes).unwrap()).unwrap();
+
+        assert_eq!(Module::program_to_bytes(&loaded), bytes);
+        assert!(
+            load_modules(&Instruction::from_bytecode(&modules[0].to_bytes()).unwrap()).is_err()
+        );
+    }
 }
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use crate::{DyModule, NativeFunction, Value};
+
+// In-process module of Rust closures, called through the same path as plugin modules
+pub struct NativeModule {
+    pub name: String,
+    pub fns: HashMap<String, NativeFunction>,
+}
+
+impl NativeModule {
+    pub fn new(name: &str) -> NativeModule {
+        NativeModule {
+            name: name.to_string(),
+            fns: HashMap::new(),
+        }
+    }
+
+    pub fn function<F>(mut self, name: &str, function: F) -> NativeModule
+    where
+        F: FnMut(Vec<Value>) -> Result<Option<Value>, String> + Send + 'static,
+    {
+        self.fns.insert(name.to_string(), Box::new(function));
+        self
+    }
+}
+
+impl From<NativeModule> for DyModule {
+    fn from(module: NativeModule) -> Self {
+        DyModule {
+            name: module.name,
+            fns: module.fns,
+            signatures: HashMap::new(),
+            lib: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{asm::assemble, load_modules, VirtualMachine};
+
+    #[test]
+    fn native_module_closures_capture_host_state() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let host_log = log.clone();
+
+        let mut vm = VirtualMachine::new();
+        vm.add_native_module(
+            NativeModule::new("host")
+                .function("log", move |args| {
+                    host_log.lock().unwrap().push(format!("{:?}", args));
+                    Ok(None)
+                })
+                .function("twice", |args| match args.as_slice() {
+                    [Value::Integer(n)] => Ok(Some(Value::Integer(n * 2))),
+                    _ => Err("twice expects an integer".to_string()),
+                }),
+        );
+
+        let (modules, _) = load_modules(
+            &assemble(
+                r#"
+                (mod main
+                    (fn run
+                        (i32.const 21) (call host twice 1)
+                        (str.const "done") (call host log 2)))
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        vm.add_modules(modules).unwrap();
+
+        vm.call("main", "run", vec![]);
+
+        assert_eq!(*log.lock().unwrap(), vec!["[42, \"done\"]".to_string()]);
+        assert!(vm.has_function("host", "twice"));
+    }
+}
@@ -16,11 +16,7 @@ impl Parser {
     pub(crate) fn parse(&mut self) -> Result<Vec<SExpr>, String> {
         let mut sexprs = vec![];
 
-        loop {
-            let Some(sexpr) = self.parse_sexp()? else {
-                break;
-            };
-
+        while let Some(sexpr) = self.parse_sexp()? {
             sexprs.push(sexpr);
         }
 
@@ -59,18 +55,14 @@ impl Parser {
 
             Ok(Some(SExpr::List(args)))
         } else {
-            return Err(format!("Unexpected token: {}", token));
+            Err(format!("Unexpected token: {}", token))
         }
     }
 
     fn next_token(&mut self) -> Option<String> {
         let mut token = String::new();
 
-        loop {
-            let Some(char) = self.source.chars().nth(self.position) else {
-                break;
-            };
-
+        while let Some(char) = self.source.chars().nth(self.position) {
             match char {
                 '(' | ')' => {
                     if !token.is_empty() {
@@ -90,9 +82,7 @@ impl Parser {
                     self.position += 1;
 
                     loop {
-                        let Some(char) = self.source.chars().nth(self.position) else {
-                            return None;
-                        };
+                        let char = self.source.chars().nth(self.position)?;
 
                         if char == '"' {
                             self.position += 1;
@@ -113,9 +103,7 @@ impl Parser {
                     self.position += 1;
 
                     loop {
-                        let Some(char) = self.source.chars().nth(self.position) else {
-                            return None;
-                        };
+                        let char = self.source.chars().nth(self.position)?;
 
                         if char == '\n' {
                             break;
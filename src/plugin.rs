@@ -0,0 +1,381 @@
+use std::{ffi::c_char, slice};
+
+use crate::Value;
+
+// Version of the plugin interface, bumped on any change to the types or symbols below
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+// Symbol every plugin exports as `extern "C" fn() -> u32` returning PLUGIN_ABI_VERSION
+pub const PLUGIN_ABI_VERSION_SYMBOL: &str = "ms_plugin_abi_version";
+
+// Optional symbol `extern "C" fn() -> *const PluginManifest` listing the plugin functions
+pub const PLUGIN_MANIFEST_SYMBOL: &str = "ms_plugin_manifest";
+
+// Status returned by plugin functions, on error the result holds the message as a string
+pub const PLUGIN_OK: i32 = 0;
+pub const PLUGIN_ERROR: i32 = 1;
+
+// Tags of FfiValue
+pub const FFI_VOID: u32 = 0; // No value, the function returns nothing
+pub const FFI_NULL: u32 = 1;
+pub const FFI_BOOLEAN: u32 = 2;
+pub const FFI_INTEGER: u32 = 3;
+pub const FFI_FLOAT: u32 = 4;
+pub const FFI_STRING: u32 = 5;
+pub const FFI_ANY: u32 = u32::MAX; // Parameter accepting any value
+
+// Parameter count of functions accepting any number of arguments
+pub const PLUGIN_VARIADIC: u32 = u32::MAX;
+
+// Borrowed UTF-8 string, not null terminated
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FfiStr {
+    pub ptr: *const c_char,
+    pub len: usize,
+}
+
+impl FfiStr {
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` readable bytes, or be null.
+    pub unsafe fn as_str(&self) -> Option<&str> {
+        if self.ptr.is_null() {
+            return Some("");
+        }
+
+        let bytes = slice::from_raw_parts(self.ptr as *const u8, self.len);
+        std::str::from_utf8(bytes).ok()
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union FfiPayload {
+    pub boolean: u8,
+    pub integer: i32,
+    pub float: f32,
+    pub string: FfiStr,
+}
+
+// FFI-safe value passed across the plugin boundary
+//
+// Strings passed as arguments are owned by the host and only valid during the call. Strings
+// returned by a plugin must stay valid until the plugin is called again, the host copies them
+// as soon as the function returns.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FfiValue {
+    pub tag: u32,
+    pub payload: FfiPayload,
+}
+
+// Signature of every function exported by a plugin
+pub type PluginFunction =
+    unsafe extern "C" fn(args: *const FfiValue, argc: usize, result: *mut FfiValue) -> i32;
+
+pub type PluginAbiVersion = unsafe extern "C" fn() -> u32;
+
+pub type PluginManifestFn = unsafe extern "C" fn() -> *const PluginManifest;
+
+// Description of a function exported by a plugin
+#[repr(C)]
+pub struct PluginFunctionInfo {
+    pub name: FfiStr,
+    pub function: PluginFunction,
+    pub param_count: u32,        // PLUGIN_VARIADIC for any number of arguments
+    pub param_types: *const u32, // param_count FFI tags, null when parameters are untyped
+    pub result_type: u32,        // FFI tag of the result, FFI_VOID when nothing is returned
+}
+
+// List of the functions of a plugin, must stay valid while the plugin is loaded
+#[repr(C)]
+pub struct PluginManifest {
+    pub functions: *const PluginFunctionInfo,
+    pub function_count: usize,
+}
+
+// Arity and types of a host function, checked before every call
+#[derive(Debug, Clone, PartialEq)]
+pub struct NativeSignature {
+    pub param_count: Option<u32>, // None for variadic functions
+    pub param_types: Vec<u32>,    // FFI tags of the parameters, empty when untyped
+    pub result_type: u32,
+}
+
+impl NativeSignature {
+    pub fn check(&self, name: &str, args: &[Value]) -> Result<(), String> {
+        if let Some(param_count) = self.param_count {
+            if args.len() != param_count as usize {
+                return Err(format!(
+                    "Function \"{}\" expects {} arguments, found {}",
+                    name,
+                    param_count,
+                    args.len()
+                ));
+            }
+        }
+
+        for (index, (arg, tag)) in args.iter().zip(self.param_types.iter()).enumerate() {
+            if *tag != FFI_ANY && ffi_tag(arg) != Some(*tag) {
+                return Err(format!(
+                    "Function \"{}\" argument {} has an invalid type: {:?}",
+                    name, index, arg
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// FFI tag of a value, None for values that can't cross the plugin boundary
+pub fn ffi_tag(value: &Value) -> Option<u32> {
+    match value {
+        Value::Null => Some(FFI_NULL),
+        Value::Boolean(_) => Some(FFI_BOOLEAN),
+        Value::Integer(_) => Some(FFI_INTEGER),
+        Value::Float(_) => Some(FFI_FLOAT),
+        Value::String(_) => Some(FFI_STRING),
+        _ => None,
+    }
+}
+
+// Export the ABI version handshake symbol from a plugin crate
+#[macro_export]
+macro_rules! export_plugin_abi_version {
+    () => {
+        #[no_mangle]
+        pub extern "C" fn ms_plugin_abi_version() -> u32 {
+            $crate::PLUGIN_ABI_VERSION
+        }
+    };
+}
+
+impl FfiValue {
+    pub fn void() -> FfiValue {
+        FfiValue {
+            tag: FFI_VOID,
+            payload: FfiPayload { integer: 0 },
+        }
+    }
+
+    pub fn null() -> FfiValue {
+        FfiValue {
+            tag: FFI_NULL,
+            payload: FfiPayload { integer: 0 },
+        }
+    }
+
+    pub fn boolean(value: bool) -> FfiValue {
+        FfiValue {
+            tag: FFI_BOOLEAN,
+            payload: FfiPayload {
+                boolean: value as u8,
+            },
+        }
+    }
+
+    pub fn integer(value: i32) -> FfiValue {
+        FfiValue {
+            tag: FFI_INTEGER,
+            payload: FfiPayload { integer: value },
+        }
+    }
+
+    pub fn float(value: f32) -> FfiValue {
+        FfiValue {
+            tag: FFI_FLOAT,
+            payload: FfiPayload { float: value },
+        }
+    }
+
+    // The string must outlive every use of the returned value
+    pub fn string(value: &str) -> FfiValue {
+        FfiValue {
+            tag: FFI_STRING,
+            payload: FfiPayload {
+                string: FfiStr {
+                    ptr: value.as_ptr() as *const c_char,
+                    len: value.len(),
+                },
+            },
+        }
+    }
+
+    // Borrow a value as an FfiValue, only primitive values cross the plugin boundary
+    pub fn from_value(value: &Value) -> Result<FfiValue, String> {
+        match value {
+            Value::Null => Ok(FfiValue::null()),
+            Value::Boolean(value) => Ok(FfiValue::boolean(*value)),
+            Value::Integer(value) => Ok(FfiValue::integer(*value)),
+            Value::Float(value) => Ok(FfiValue::float(*value)),
+            Value::String(value) => Ok(FfiValue::string(value)),
+            _ => Err(format!("Value {:?} can't be passed to a plugin", value)),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// String values must point to `len` readable bytes.
+    pub unsafe fn as_str(&self) -> Option<&str> {
+        if self.tag != FFI_STRING {
+            return None;
+        }
+
+        let string = &self.payload.string;
+        string.as_str()
+    }
+
+    /// # Safety
+    ///
+    /// String values must point to `len` readable bytes.
+    pub unsafe fn to_value(&self) -> Result<Option<Value>, String> {
+        match self.tag {
+            FFI_VOID => Ok(None),
+            FFI_NULL => Ok(Some(Value::Null)),
+            FFI_BOOLEAN => Ok(Some(Value::Boolean(self.payload.boolean != 0))),
+            FFI_INTEGER => Ok(Some(Value::Integer(self.payload.integer))),
+            FFI_FLOAT => Ok(Some(Value::Float(self.payload.float))),
+            FFI_STRING => match self.as_str() {
+                Some(value) => Ok(Some(Value::String(value.to_string()))),
+                None => Err("Plugin returned an invalid UTF-8 string".to_string()),
+            },
+            tag => Err(format!("Plugin returned an invalid value tag: {}", tag)),
+        }
+    }
+}
+
+/// Call a plugin function converting the arguments and the result
+///
+/// # Safety
+///
+/// `function` must follow the plugin ABI described in this module.
+pub unsafe fn call_plugin_function(
+    function: PluginFunction,
+    args: &[Value],
+) -> Result<Option<Value>, String> {
+    let args = args
+        .iter()
+        .map(FfiValue::from_value)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut result = FfiValue::void();
+    let status = function(args.as_ptr(), args.len(), &mut result);
+
+    match status {
+        PLUGIN_OK => result.to_value(),
+        _ => match result.as_str() {
+            Some(message) => Err(message.to_string()),
+            None => Err(format!("Plugin function failed with status {}", status)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DyModule, NativeModule};
+
+    unsafe extern "C" fn concat(args: *const FfiValue, argc: usize, result: *mut FfiValue) -> i32 {
+        thread_local! {
+            static BUFFER: std::cell::RefCell<String> = const { std::cell::RefCell::new(String::new()) };
+        }
+
+        let args = slice::from_raw_parts(args, argc);
+        let mut output = String::new();
+
+        for arg in args {
+            match arg.as_str() {
+                Some(value) => output.push_str(value),
+                None => {
+                    *result = FfiValue::string("concat expects strings");
+                    return PLUGIN_ERROR;
+                }
+            }
+        }
+
+        BUFFER.with(|buffer| {
+            *buffer.borrow_mut() = output;
+            *result = FfiValue::string(buffer.borrow().as_str());
+        });
+
+        PLUGIN_OK
+    }
+
+    #[test]
+    fn plugin_function_converts_values() {
+        let result = unsafe {
+            call_plugin_function(
+                concat,
+                &[
+                    Value::String("a".to_string()),
+                    Value::String("b".to_string()),
+                ],
+            )
+        };
+
+        assert_eq!(format!("{:?}", result), "Ok(Some(\"ab\"))");
+    }
+
+    #[test]
+    fn plugin_function_reports_errors() {
+        let result = unsafe { call_plugin_function(concat, &[Value::Integer(1)]) };
+
+        assert_eq!(result.unwrap_err(), "concat expects strings");
+    }
+
+    #[test]
+    fn manifest_imports_functions() {
+        static PARAM_TYPES: [u32; 2] = [FFI_STRING, FFI_STRING];
+        let name = "concat";
+        let functions = [PluginFunctionInfo {
+            name: FfiStr {
+                ptr: name.as_ptr() as *const c_char,
+                len: name.len(),
+            },
+            function: concat,
+            param_count: 2,
+            param_types: PARAM_TYPES.as_ptr(),
+            result_type: FFI_STRING,
+        }];
+        let manifest = PluginManifest {
+            functions: functions.as_ptr(),
+            function_count: functions.len(),
+        };
+
+        let mut module = DyModule::from(NativeModule::new("test"));
+        module
+            .import_functions(
+                &manifest,
+                &[("concat".to_string(), Some("join".to_string()))],
+            )
+            .unwrap();
+
+        let args = vec![
+            Value::String("a".to_string()),
+            Value::String("b".to_string()),
+        ];
+        assert_eq!(
+            format!("{:?}", module.call("join", args)),
+            "Ok(Some(\"ab\"))"
+        );
+
+        let error = module.call("join", vec![Value::String("a".to_string())]);
+        assert_eq!(
+            error.unwrap_err(),
+            "Function \"join\" expects 2 arguments, found 1"
+        );
+
+        let error = module.call("join", vec![Value::Integer(1), Value::Integer(2)]);
+        assert!(error
+            .unwrap_err()
+            .contains("argument 0 has an invalid type"));
+
+        let error = module.import_functions(&manifest, &[("missing".to_string(), None)]);
+        assert_eq!(
+            error.unwrap_err(),
+            "Plugin \"test\" does not export missing, available functions: concat"
+        );
+    }
+}
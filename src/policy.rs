@@ -0,0 +1,209 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+// Capabilities granted to scripts, script modules are always reachable while host modules
+// (native and plugin) and plugin libraries must be allowed explicitly once restricted
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    pub modules: Option<HashSet<String>>, // Host modules callable as a whole, None allows all
+    pub functions: HashSet<(String, String)>, // Single host functions allowed as (module, function)
+    pub library_paths: Option<Vec<PathBuf>>, // Directories or files plugins may be loaded from, None allows all
+}
+
+impl Policy {
+    // Policy that allows every module, function and library
+    pub fn allow_all() -> Policy {
+        Policy::default()
+    }
+
+    // Policy that denies every host module and library until allowed
+    pub fn deny_all() -> Policy {
+        Policy {
+            modules: Some(HashSet::new()),
+            functions: HashSet::new(),
+            library_paths: Some(Vec::new()),
+        }
+    }
+
+    pub fn allow_module(mut self, module: &str) -> Policy {
+        if let Some(modules) = &mut self.modules {
+            modules.insert(module.to_string());
+        }
+
+        self
+    }
+
+    pub fn allow_function(mut self, module: &str, function: &str) -> Policy {
+        self.functions
+            .insert((module.to_string(), function.to_string()));
+        self
+    }
+
+    pub fn allow_library_path<P: AsRef<Path>>(mut self, path: P) -> Policy {
+        if let Some(paths) = &mut self.library_paths {
+            paths.push(normalize(path.as_ref()));
+        }
+
+        self
+    }
+
+    // Check a call to a function of a host module
+    pub fn check_call(&self, module: &str, function: &str) -> Result<(), String> {
+        let Some(modules) = &self.modules else {
+            return Ok(());
+        };
+
+        if modules.contains(module)
+            || self
+                .functions
+                .contains(&(module.to_string(), function.to_string()))
+        {
+            return Ok(());
+        }
+
+        Err(format!(
+            "Permission denied: call to {}.{} is not allowed",
+            module, function
+        ))
+    }
+
+    // Check that a plugin library may be loaded from the given path, returns the path to load.
+    // When directories are restricted it is the resolved path that was checked, so a symlink
+    // swapped after the check can't point somewhere else
+    pub fn check_library(&self, path: &Path) -> Result<PathBuf, String> {
+        let Some(paths) = &self.library_paths else {
+            return Ok(path.to_path_buf());
+        };
+
+        // A path that can't be resolved could point anywhere once it exists
+        let Ok(path) = path.canonicalize() else {
+            return Err(format!(
+                "Permission denied: library \"{}\" can't be resolved",
+                path.display()
+            ));
+        };
+
+        if paths.iter().any(|allowed| path.starts_with(allowed)) {
+            return Ok(path);
+        }
+
+        Err(format!(
+            "Permission denied: loading library \"{}\" is not allowed",
+            path.display()
+        ))
+    }
+}
+
+// Allowed directories may not exist yet, they are kept as given in that case
+fn normalize(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_allowlists() {
+        let policy = Policy::deny_all()
+            .allow_module("std.math")
+            .allow_function("std.io", "println");
+
+        assert!(policy.check_call("std.math", "sqrt").is_ok());
+        assert!(policy.check_call("std.io", "println").is_ok());
+        assert!(policy.check_call("std.io", "read_line").is_err());
+        assert!(Policy::allow_all()
+            .check_call("std.io", "read_line")
+            .is_ok());
+    }
+
+    #[test]
+    fn policy_library_paths() {
+        let dir = std::env::temp_dir().join(format!("ms-policy-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("plugins")).unwrap();
+        std::fs::write(dir.join("plugins/libfoo.so"), b"").unwrap();
+        std::fs::write(dir.join("libfoo.so"), b"").unwrap();
+
+        let policy = Policy::deny_all().allow_library_path(dir.join("plugins"));
+
+        assert!(policy.check_library(&dir.join("plugins/libfoo.so")).is_ok());
+        assert_eq!(
+            Policy::allow_all().check_library(Path::new("libfoo.so")),
+            Ok(PathBuf::from("libfoo.so"))
+        );
+
+        // The checked path is the one to load, links are resolved before the check
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("plugins/libfoo.so"), dir.join("plugins/link.so"))
+                .unwrap();
+
+            assert_eq!(
+                policy.check_library(&dir.join("plugins/link.so")),
+                Ok(dir.join("plugins/libfoo.so").canonicalize().unwrap())
+            );
+        }
+        assert!(policy
+            .check_library(&dir.join("plugins/../libfoo.so"))
+            .is_err());
+        assert!(policy.check_library(&dir.join("libfoo.so")).is_err());
+        assert_eq!(
+            policy.check_library(&dir.join("plugins/missing.so")),
+            Err(format!(
+                "Permission denied: library \"{}\" can't be resolved",
+                dir.join("plugins/missing.so").display()
+            ))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loader_denies_libraries() {
+        let dir = std::env::temp_dir().join(format!("ms-policy-loader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(crate::SearchPath::library_file_name("foo")), b"").unwrap();
+
+        let code = crate::asm::assemble("(mod.load foo (fn.get foo))").unwrap();
+        let result = crate::Loader::new()
+            .search_path(crate::SearchPath::new().add_dir(&dir))
+            .policy(Policy::deny_all())
+            .load(&code);
+
+        assert!(result.err().unwrap().starts_with("Permission denied"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Permission denied: call to host.secret is not allowed")]
+    fn denied_calls_do_not_execute() {
+        let mut vm = crate::VirtualMachine::new();
+        vm.add_native_module(
+            crate::NativeModule::new("host")
+                .function("public", |_| Ok(None))
+                .function("secret", |_| panic!("secret executed")),
+        );
+        vm.set_policy(Policy::deny_all().allow_function("host", "public"));
+
+        vm.call("host", "public", vec![]);
+        vm.call("host", "secret", vec![]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Permission denied: call to host.secret is not allowed")]
+    fn policies_apply_per_call() {
+        let mut vm = crate::VirtualMachine::new();
+        vm.add_native_module(
+            crate::NativeModule::new("host")
+                .function("secret", |_| Ok(Some(crate::Value::Integer(1)))),
+        );
+
+        vm.call("host", "secret", vec![]);
+        assert_eq!(vm.stack.len(), 1);
+
+        vm.call_with_policy("host", "secret", vec![], Policy::deny_all());
+    }
+}
@@ -0,0 +1,419 @@
+use std::{fs, path::PathBuf, time::SystemTime};
+
+use crate::{
+    asm::assemble, read_code, resolve, ByteCode, Code, Container, Instruction, Module, Value,
+    VirtualMachine,
+};
+
+// Differences between the loaded and the reloaded version of a module
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReloadReport {
+    pub module: String,
+    pub added_functions: Vec<String>,
+    pub changed_functions: Vec<String>,
+    pub removed_functions: Vec<String>,
+    pub kept_globals: Vec<String>, // Globals whose value survived the reload
+    pub new_globals: Vec<String>,  // Globals evaluated by their init code
+    pub removed_globals: Vec<String>, // Globals whose value was dropped
+    pub incompatible: Vec<String>, // Changes that may break running state or callers
+}
+
+impl ReloadReport {
+    pub fn is_compatible(&self) -> bool {
+        self.incompatible.is_empty()
+    }
+}
+
+impl VirtualMachine {
+    // Replace a loaded module between calls, globals keep their values when they still exist
+    pub fn reload_module(&mut self, module: Module) -> Result<ReloadReport, String> {
+        let mut reports = self.reload_modules(vec![module])?;
+        Ok(reports.remove(0))
+    }
+
+    // Reload several modules together, nothing is replaced when one of them can't be
+    pub fn reload_modules(&mut self, modules: Vec<Module>) -> Result<Vec<ReloadReport>, String> {
+        let order = self.check_reload(&modules)?;
+        let mut modules: Vec<Option<Module>> = modules.into_iter().map(Some).collect();
+        let mut reports: Vec<Option<ReloadReport>> = modules.iter().map(|_| None).collect();
+
+        for index in order {
+            if let Some(module) = modules[index].take() {
+                reports[index] = Some(self.replace_module(module));
+            }
+        }
+
+        Ok(reports.into_iter().flatten().collect())
+    }
+
+    // New code is resolved like added modules, and functions can only be removed once no other
+    // module calls them. Returns the order to replace the modules in
+    fn check_reload(&self, modules: &[Module]) -> Result<Vec<usize>, String> {
+        if let (Some(module), false) = (modules.first(), self.module_stack.is_empty()) {
+            return Err(format!(
+                "Can't reload \"{}\" while a call is running",
+                module.name
+            ));
+        }
+
+        let mut callers: Vec<&Module> = self
+            .modules
+            .values()
+            .filter(|caller| modules.iter().all(|module| module.name != caller.name))
+            .collect();
+        callers.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for module in modules.iter() {
+            let Some(old) = self.modules.get(&module.name) else {
+                continue;
+            };
+
+            let mut removed: Vec<&String> = old
+                .functions
+                .keys()
+                .filter(|name| !module.functions.contains_key(*name))
+                .collect();
+            removed.sort();
+
+            for function in removed {
+                for caller in callers.iter() {
+                    let mut code = caller
+                        .functions
+                        .values()
+                        .map(|f| &f.code)
+                        .chain(caller.globals.iter().map(|g| &g.init));
+
+                    if code.any(|code| calls(code, &module.name, function)) {
+                        return Err(format!(
+                            "Can't reload \"{}\", \"{}\" still calls removed function \"{}.{}\"",
+                            module.name, caller.name, module.name, function
+                        ));
+                    }
+                }
+            }
+        }
+
+        resolve(modules, self)
+    }
+
+    fn replace_module(&mut self, module: Module) -> ReloadReport {
+        let Some(mut old) = self.modules.remove(&module.name) else {
+            let mut report = ReloadReport {
+                module: module.name.clone(),
+                added_functions: module.functions.keys().cloned().collect(),
+                new_globals: module.globals.iter().map(|g| g.name.clone()).collect(),
+                ..Default::default()
+            };
+            report.added_functions.sort();
+            self.insert_module(module);
+            return report;
+        };
+
+        let mut module = module;
+        let mut report = diff(&old, &module);
+        let mut pending = Vec::new();
+
+        for (index, global) in module.globals.iter_mut().enumerate() {
+            match old.get_global_mut(&global.name) {
+                Some(previous) => {
+                    global.value = std::mem::replace(&mut previous.value, Value::Null)
+                }
+                None => pending.push(index),
+            }
+        }
+
+        let name = module.name.clone();
+        self.modules.insert(name.clone(), module);
+
+        for index in pending {
+            self.init_global(&name, index);
+        }
+
+        report.kept_globals.sort();
+        report
+    }
+}
+
+// Whether code calls or makes a closure of a function, including nested blocks
+fn calls(code: &Code, module: &str, function: &str) -> bool {
+    code.iter().any(|instruction| match instruction {
+        Instruction::Call {
+            module: m,
+            function: f,
+            ..
+        }
+        | Instruction::TailCall {
+            module: m,
+            function: f,
+            ..
+        }
+        | Instruction::MakeClosure {
+            module: m,
+            function: f,
+            ..
+        } => m == module && f == function,
+        Instruction::Loop { block } => calls(block, module, function),
+        Instruction::Then {
+            then_block,
+            else_block,
+        } => calls(then_block, module, function) || calls(else_block, module, function),
+        Instruction::Switch { cases, default } => {
+            cases.iter().any(|case| calls(case, module, function))
+                || calls(default, module, function)
+        }
+        _ => false,
+    })
+}
+
+fn diff(old: &Module, new: &Module) -> ReloadReport {
+    let mut report = ReloadReport {
+        module: new.name.clone(),
+        ..Default::default()
+    };
+
+    for (name, function) in new.functions.iter() {
+        match old.get_function(name) {
+            None => report.added_functions.push(name.clone()),
+            Some(previous) => {
+                if previous.signature != function.signature {
+                    report
+                        .incompatible
+                        .push(format!("Function \"{}\" changed its signature", name));
+                }
+
+                if Instruction::code_to_bytes(&previous.code)
+                    != Instruction::code_to_bytes(&function.code)
+                {
+                    report.changed_functions.push(name.clone());
+                }
+            }
+        }
+    }
+
+    for name in old.functions.keys() {
+        if !new.functions.contains_key(name) {
+            report.removed_functions.push(name.clone());
+            report
+                .incompatible
+                .push(format!("Function \"{}\" was removed", name));
+        }
+    }
+
+    for global in new.globals.iter() {
+        match old.get_global(&global.name) {
+            Some(_) => report.kept_globals.push(global.name.clone()),
+            None => report.new_globals.push(global.name.clone()),
+        }
+    }
+
+    for global in old.globals.iter() {
+        if new.get_global(&global.name).is_none() {
+            report.removed_globals.push(global.name.clone());
+        }
+    }
+
+    for (name, fields) in old.structs.iter() {
+        match new.structs.get(name) {
+            Some(new_fields) if new_fields != fields => report
+                .incompatible
+                .push(format!("Struct \"{}\" changed its fields", name)),
+            None => report
+                .incompatible
+                .push(format!("Struct \"{}\" was removed", name)),
+            _ => {}
+        }
+    }
+
+    report.added_functions.sort();
+    report.changed_functions.sort();
+    report.removed_functions.sort();
+    report.incompatible.sort();
+    report
+}
+
+// Polls a source or bytecode file and returns its code each time it changes on disk
+pub struct SourceWatcher {
+    pub path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl SourceWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> SourceWatcher {
+        SourceWatcher {
+            path: path.into(),
+            modified: None,
+        }
+    }
+
+    // Code of the file if it changed since the last poll, the first poll always reads it
+    pub fn poll(&mut self) -> Result<Option<Code>, String> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| format!("Failed to read \"{}\": {}", self.path.display(), e))?;
+
+        if self.modified == Some(modified) {
+            return Ok(None);
+        }
+
+        let bytes = fs::read(&self.path)
+            .map_err(|e| format!("Failed to read \"{}\": {}", self.path.display(), e))?;
+
+        // Bytecode always starts with its version or the container magic, source never does
+        let code = if bytes.first() == Some(&(ByteCode::Version as u8))
+            || Container::is_container(&bytes)
+        {
+            read_code(&bytes)?
+        } else {
+            let source = String::from_utf8(bytes)
+                .map_err(|_| format!("\"{}\" is not valid UTF-8", self.path.display()))?;
+            assemble(&source)?
+        };
+
+        // Only remember the change once the file was parsed, a broken save is retried
+        self.modified = Some(modified);
+        Ok(Some(code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load_modules;
+
+    fn modules(source: &str) -> Vec<Module> {
+        load_modules(&assemble(source).unwrap()).unwrap().0
+    }
+
+    fn module(source: &str) -> Module {
+        modules(source).remove(0)
+    }
+
+    #[test]
+    fn reload_keeps_globals() {
+        let mut vm = VirtualMachine::new();
+        vm.add_module(module(
+            r#"
+            (mod main
+                (struct Point x y)
+                (global count (i32.const 0))
+                (global old (i32.const 1))
+                (fn incr (global.get count) (op.inc) (global.set count))
+                (fn get (results 1) (global.get count))
+                (fn gone))
+            "#,
+        ))
+        .unwrap();
+
+        vm.call("main", "incr", vec![]);
+        vm.call("main", "incr", vec![]);
+
+        let report = vm
+            .reload_module(module(
+                r#"
+                (mod main
+                    (struct Point x y z)
+                    (global extra (i32.const 7))
+                    (global count (i32.const 0))
+                    (fn incr (global.get count) (i32.const 10) (op.add) (global.set count))
+                    (fn get (params 1) (results 1) (global.get count)))
+                "#,
+            ))
+            .unwrap();
+
+        assert_eq!(report.changed_functions, vec!["get", "incr"]);
+        assert_eq!(report.removed_functions, vec!["gone"]);
+        assert_eq!(report.kept_globals, vec!["count"]);
+        assert_eq!(report.new_globals, vec!["extra"]);
+        assert_eq!(report.removed_globals, vec!["old"]);
+        assert_eq!(
+            report.incompatible,
+            vec![
+                "Function \"get\" changed its signature",
+                "Function \"gone\" was removed",
+                "Struct \"Point\" changed its fields"
+            ]
+        );
+
+        vm.call("main", "incr", vec![]);
+
+        assert_eq!(format!("{:?}", vm.get_global("main", "count")), "Some(12)");
+        assert_eq!(format!("{:?}", vm.get_global("main", "extra")), "Some(7)");
+    }
+
+    #[test]
+    fn reload_keeps_functions_other_modules_call() {
+        let mut vm = VirtualMachine::new();
+        vm.add_modules(modules(
+            r#"
+            (mod app (fn run (results 1) (call lib get 0)))
+            (mod lib (fn get (results 1) (i32.const 1)))
+            (mod other (fn value (results 1) (i32.const 1)))
+            "#,
+        ))
+        .unwrap();
+
+        // Nothing is replaced when one module of the batch can't be
+        let error = vm
+            .reload_modules(modules(
+                r#"
+                (mod other (fn value (results 1) (i32.const 2)))
+                (mod lib (fn renamed (results 1) (i32.const 1)))
+                "#,
+            ))
+            .unwrap_err();
+
+        assert_eq!(
+            error,
+            "Can't reload \"lib\", \"app\" still calls removed function \"lib.get\""
+        );
+
+        vm.call("app", "run", vec![]);
+        vm.call("other", "value", vec![]);
+        assert_eq!(format!("{:?}", vm.stack), "[1, 1]");
+    }
+
+    #[test]
+    fn reload_resolves_new_calls() {
+        let mut vm = VirtualMachine::new();
+        vm.add_modules(modules("(mod app (fn run (results 1) (i32.const 1)))"))
+            .unwrap();
+
+        let error = vm
+            .reload_module(module(
+                "(mod app (fn run (results 1) (call lib get 0) (call app gone 0)))",
+            ))
+            .unwrap_err();
+
+        assert_eq!(
+            error,
+            "Unresolved modules:\n  \
+            app: fn run uses unknown module \"lib\"\n  \
+            app: fn run uses unknown function \"app.gone\""
+        );
+
+        // Modules reloaded together can call each other
+        vm.reload_modules(modules(
+            r#"
+            (mod app (fn run (results 1) (call lib get 0)))
+            (mod lib (global one (i32.const 2)) (fn get (results 1) (global.get one)))
+            "#,
+        ))
+        .unwrap();
+
+        vm.call("app", "run", vec![]);
+        assert_eq!(format!("{:?}", vm.stack), "[2]");
+    }
+
+    #[test]
+    fn watcher_reads_changes() {
+        let path = std::env::temp_dir().join(format!("ms_reload_{}.ms", std::process::id()));
+        fs::write(&path, "(mod main (fn run))").unwrap();
+
+        let mut watcher = SourceWatcher::new(&path);
+        assert!(watcher.poll().unwrap().is_some());
+        assert!(watcher.poll().unwrap().is_none());
+
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.poll().is_err());
+    }
+}
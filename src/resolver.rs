@@ -0,0 +1,295 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{Code, Instruction, Module, VirtualMachine};
+
+impl VirtualMachine {
+    // Add modules after checking every import and call target, in initialization order
+    pub fn add_modules(&mut self, modules: Vec<Module>) -> Result<(), String> {
+        let order = resolve(&modules, self)?;
+        let mut modules: Vec<Option<Module>> = modules.into_iter().map(Some).collect();
+
+        for index in order {
+            if let Some(module) = modules[index].take() {
+                self.insert_module(module);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Check the symbols used by new modules against themselves and the modules already in the
+// virtual machine, returns the indexes of the modules with dependencies first
+pub fn resolve(modules: &[Module], vm: &VirtualMachine) -> Result<Vec<usize>, String> {
+    let by_name: HashMap<&str, usize> = modules
+        .iter()
+        .enumerate()
+        .map(|(index, module)| (module.name.as_str(), index))
+        .collect();
+
+    let has_function = |module: &str, function: &str| -> Option<bool> {
+        if let Some(index) = by_name.get(module) {
+            Some(modules[*index].functions.contains_key(function))
+        } else if vm.modules.contains_key(module) || vm.dymodules.contains_key(module) {
+            Some(vm.has_function(module, function))
+        } else {
+            None
+        }
+    };
+
+    let mut errors = Vec::new();
+    let mut dependencies: Vec<Vec<usize>> = vec![Vec::new(); modules.len()];
+
+    for (index, module) in modules.iter().enumerate() {
+        let mut symbols: Vec<(String, &str, &str)> = Vec::new();
+
+        let mut imports: Vec<(&String, &Vec<String>)> = module.imports.iter().collect();
+        imports.sort();
+
+        for (target, functions) in imports {
+            if functions.is_empty() {
+                symbols.push(("import".to_string(), target, ""));
+            }
+
+            for function in functions.iter() {
+                symbols.push(("import".to_string(), target, function));
+            }
+        }
+
+        let mut functions: Vec<_> = module.functions.values().collect();
+        functions.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for function in functions {
+            visit_calls(&function.code, &mut |target, name| {
+                symbols.push((format!("fn {}", function.name), target, name));
+            });
+        }
+
+        for global in module.globals.iter() {
+            visit_calls(&global.init, &mut |target, name| {
+                symbols.push((format!("global {}", global.name), target, name));
+            });
+        }
+
+        let mut reported = HashSet::new();
+
+        for (origin, target, function) in symbols {
+            match has_function(target, function) {
+                None if reported.insert((target, "")) => {
+                    errors.push(format!(
+                        "{}: {} uses unknown module \"{}\"",
+                        module.name, origin, target
+                    ));
+                }
+                Some(false) if !function.is_empty() && reported.insert((target, function)) => {
+                    errors.push(format!(
+                        "{}: {} uses unknown function \"{}.{}\"",
+                        module.name, origin, target, function
+                    ));
+                }
+                _ => {}
+            }
+
+            if let Some(dependency) = by_name.get(target) {
+                if *dependency != index && !dependencies[index].contains(dependency) {
+                    dependencies[index].push(*dependency);
+                }
+            }
+        }
+    }
+
+    let order = order(modules, &dependencies, &mut errors);
+
+    if !errors.is_empty() {
+        return Err(format!("Unresolved modules:\n  {}", errors.join("\n  ")));
+    }
+
+    Ok(order)
+}
+
+// Depth first ordering, a cycle is only an error when one of its modules has globals whose
+// init code could observe another module before it is initialized
+fn order(modules: &[Module], dependencies: &[Vec<usize>], errors: &mut Vec<String>) -> Vec<usize> {
+    fn visit(
+        index: usize,
+        modules: &[Module],
+        dependencies: &[Vec<usize>],
+        state: &mut [u8], // 0 unvisited, 1 in progress, 2 done
+        path: &mut Vec<usize>,
+        order: &mut Vec<usize>,
+        errors: &mut Vec<String>,
+    ) {
+        state[index] = 1;
+        path.push(index);
+
+        for dependency in dependencies[index].iter() {
+            match state[*dependency] {
+                0 => visit(
+                    *dependency,
+                    modules,
+                    dependencies,
+                    state,
+                    path,
+                    order,
+                    errors,
+                ),
+                1 => {
+                    let start = path.iter().position(|i| i == dependency).unwrap();
+                    let cycle = &path[start..];
+
+                    if cycle.iter().any(|i| !modules[*i].globals.is_empty()) {
+                        let mut names: Vec<&str> =
+                            cycle.iter().map(|i| modules[*i].name.as_str()).collect();
+                        names.push(&modules[*dependency].name);
+
+                        errors.push(format!(
+                            "Cycle between modules with globals: {}",
+                            names.join(" -> ")
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        path.pop();
+        state[index] = 2;
+        order.push(index);
+    }
+
+    let mut indexes: Vec<usize> = (0..modules.len()).collect();
+    indexes.sort_by(|a, b| modules[*a].name.cmp(&modules[*b].name));
+
+    let mut state = vec![0; modules.len()];
+    let mut order = Vec::new();
+
+    for index in indexes {
+        if state[index] == 0 {
+            visit(
+                index,
+                modules,
+                dependencies,
+                &mut state,
+                &mut Vec::new(),
+                &mut order,
+                errors,
+            );
+        }
+    }
+
+    order
+}
+
+// Every (module, function) referenced by calls and closures, including nested blocks
+fn visit_calls<'a>(code: &'a Code, callback: &mut impl FnMut(&'a str, &'a str)) {
+    for instruction in code.iter() {
+        match instruction {
+            Instruction::Call {
+                module, function, ..
+            }
+            | Instruction::TailCall {
+                module, function, ..
+            }
+            | Instruction::MakeClosure {
+                module, function, ..
+            } => callback(module, function),
+            Instruction::Fn { code, .. } | Instruction::Loop { block: code } => {
+                visit_calls(code, callback)
+            }
+            Instruction::Then {
+                then_block,
+                else_block,
+            } => {
+                visit_calls(then_block, callback);
+                visit_calls(else_block, callback);
+            }
+            Instruction::Switch { cases, default } => {
+                for case in cases.iter() {
+                    visit_calls(case, callback);
+                }
+
+                visit_calls(default, callback);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, load_modules};
+
+    fn modules(source: &str) -> Vec<Module> {
+        load_modules(&assemble(source).unwrap()).unwrap().0
+    }
+
+    #[test]
+    fn add_modules_orders_initialization() {
+        let mut vm = VirtualMachine::new();
+
+        vm.add_modules(modules(
+            r#"
+            (mod app
+                (import base value)
+                (global copy (call base value 0)))
+            (mod base
+                (global seed (i32.const 42))
+                (fn value (global.get seed)))
+            "#,
+        ))
+        .unwrap();
+
+        assert_eq!(format!("{:?}", vm.get_global("app", "copy")), "Some(42)");
+    }
+
+    #[test]
+    fn add_module_resolves_against_added_modules() {
+        let source = "(mod app (fn run (call base value 0))) (mod base (fn value))";
+        let mut vm = VirtualMachine::new();
+
+        let [app, base] = <[Module; 2]>::try_from(modules(source)).ok().unwrap();
+
+        assert_eq!(
+            vm.add_module(app).unwrap_err(),
+            "Unresolved modules:\n  app: fn run uses unknown module \"base\""
+        );
+        assert!(!vm.modules.contains_key("app"));
+
+        let [app, _] = <[Module; 2]>::try_from(modules(source)).ok().unwrap();
+
+        vm.add_module(base).unwrap();
+        vm.add_module(app).unwrap();
+        assert!(vm.modules.contains_key("app"));
+    }
+
+    #[test]
+    fn resolve_reports_every_unresolved_symbol() {
+        let vm = VirtualMachine::new();
+        let error = resolve(
+            &modules(
+                r#"
+                (mod a
+                    (import b missing)
+                    (import nowhere f)
+                    (global x (call b run 0))
+                    (fn run (call a gone 0)))
+                (mod b
+                    (global y (call a run 0))
+                    (fn run))
+                "#,
+            ),
+            &vm,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error,
+            "Unresolved modules:\n  \
+            a: import uses unknown function \"b.missing\"\n  \
+            a: import uses unknown module \"nowhere\"\n  \
+            a: fn run uses unknown function \"a.gone\"\n  \
+            Cycle between modules with globals: a -> b -> a"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use crate::Constant;
+
+// Names declared by the module being assembled, resolved to indices at assembly time
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Scope {
+    pub structs: HashMap<String, Vec<String>>,
+    pub globals: Vec<String>,
+    pub constants: Option<Vec<Constant>>, // Constant pool, only available inside a module
+    pub labels: Vec<Option<String>>,      // Labels of the enclosing loops, innermost last
+}
+
+impl Scope {
+    pub fn new() -> Scope {
+        Scope::default()
+    }
+
+    pub fn module() -> Scope {
+        Scope {
+            constants: Some(Vec::new()),
+            ..Scope::default()
+        }
+    }
+
+    // Add a constant to the pool reusing an equal entry, returns None outside a module
+    pub fn intern_constant(&mut self, constant: Constant) -> Option<u32> {
+        let constants = self.constants.as_mut()?;
+
+        // Floats are compared by their bits so 0.0 and -0.0 stay different entries
+        let same = |c: &Constant| match (c, &constant) {
+            (Constant::Float(a), Constant::Float(b)) => a.to_bits() == b.to_bits(),
+            (c, constant) => c == constant,
+        };
+
+        match constants.iter().position(same) {
+            Some(index) => Some(index as u32),
+            None => {
+                constants.push(constant);
+                Some(constants.len() as u32 - 1)
+            }
+        }
+    }
+
+    // Resolve a constant reference, either a pool index or a literal interned on the fly
+    pub fn resolve_constant(&mut self, constant: &str) -> Result<u32, String> {
+        if let Ok(index) = constant.parse::<u32>() {
+            return Ok(index);
+        }
+
+        let literal = if let Ok(value) = constant.parse::<i32>() {
+            Constant::Integer(value)
+        } else if let Ok(value) = constant.parse::<f32>() {
+            Constant::Float(value)
+        } else {
+            Constant::String(constant.to_string())
+        };
+
+        self.intern_constant(literal)
+            .ok_or_else(|| format!("Constant {} used outside of a module", constant))
+    }
+
+    pub fn resolve_struct(&self, name: &str) -> Result<&Vec<String>, String> {
+        self.structs
+            .get(name)
+            .ok_or_else(|| format!("Unknown struct: {}", name))
+    }
+
+    // Resolve a global reference, either a numeric index or a declared name
+    pub fn resolve_global(&self, global: &str) -> Result<u32, String> {
+        if let Ok(index) = global.parse::<u32>() {
+            return Ok(index);
+        }
+
+        match self.globals.iter().position(|g| g == global) {
+            Some(index) => Ok(index as u32),
+            None => Err(format!("Unknown global: {}", global)),
+        }
+    }
+
+    // Resolve a loop reference, either a numeric depth or a $label of an enclosing loop
+    pub fn resolve_label(&self, label: &str) -> Result<u32, String> {
+        if let Ok(depth) = label.parse::<u32>() {
+            return self.resolve_depth(depth);
+        }
+
+        match self
+            .labels
+            .iter()
+            .rev()
+            .position(|l| l.as_deref() == Some(label))
+        {
+            Some(depth) => Ok(depth as u32),
+            None => Err(format!("Unknown loop label: {}", label)),
+        }
+    }
+
+    // Check a loop depth against the enclosing loops, 0 is the innermost one
+    pub fn resolve_depth(&self, depth: u32) -> Result<u32, String> {
+        if depth as usize >= self.labels.len() {
+            return Err(format!(
+                "Loop depth {} out of range, {} enclosing loops",
+                depth,
+                self.labels.len()
+            ));
+        }
+
+        Ok(depth)
+    }
+
+    // Field referenced by name only, resolved against the object at run time
+    pub fn is_field_name(field: &str) -> bool {
+        field.parse::<u32>().is_err() && !field.contains('.')
+    }
+
+    // Resolve a field reference, either a numeric index or <struct>.<field>
+    pub fn resolve_field(&self, field: &str) -> Result<u32, String> {
+        if let Ok(index) = field.parse::<u32>() {
+            return Ok(index);
+        }
+
+        let Some((name, field_name)) = field.split_once('.') else {
+            return Err(format!("Invalid field reference: {}", field));
+        };
+
+        let fields = self.resolve_struct(name)?;
+
+        match fields.iter().position(|f| f == field_name) {
+            Some(index) => Ok(index as u32),
+            None => Err(format!("Unknown field {} in struct {}", field_name, name)),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+// Environment variable with extra directories to search plugin libraries in
+pub const SEARCH_PATH_ENV: &str = "MS_MODULE_PATH";
+
+// Directories searched when (mod.load) names a library without a path
+#[derive(Debug, Clone, Default)]
+pub struct SearchPath {
+    pub dirs: Vec<PathBuf>,
+}
+
+impl SearchPath {
+    pub fn new() -> SearchPath {
+        SearchPath::default()
+    }
+
+    // Search path with the directories listed in MS_MODULE_PATH
+    pub fn from_env() -> SearchPath {
+        let mut search_path = SearchPath::new();
+
+        if let Some(paths) = env::var_os(SEARCH_PATH_ENV) {
+            search_path.dirs.extend(env::split_paths(&paths));
+        }
+
+        search_path
+    }
+
+    pub fn add_dir<P: AsRef<Path>>(mut self, dir: P) -> SearchPath {
+        self.dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    // Platform file name of a logical library name, e.g. foo -> libfoo.so on Linux
+    pub fn library_file_name(name: &str) -> String {
+        format!(
+            "{}{}{}",
+            env::consts::DLL_PREFIX,
+            name,
+            env::consts::DLL_SUFFIX
+        )
+    }
+
+    // Name handed to the platform loader when no file was found, so it can still search its
+    // own directories (LD_LIBRARY_PATH, system paths)
+    pub fn platform_name(name: &str) -> PathBuf {
+        if SearchPath::is_path(name) {
+            PathBuf::from(name)
+        } else {
+            PathBuf::from(SearchPath::library_file_name(name))
+        }
+    }
+
+    fn is_path(name: &str) -> bool {
+        let path = Path::new(name);
+        path.components().count() > 1 || path.extension().is_some()
+    }
+
+    // Resolve a library name to an existing file, names with a directory or extension are used
+    // as paths. The error lists every location tried.
+    pub fn resolve(&self, name: &str) -> Result<PathBuf, String> {
+        if SearchPath::is_path(name) {
+            let path = Path::new(name);
+
+            if path.exists() {
+                return Ok(path.to_path_buf());
+            }
+
+            return Err(format!(
+                "Library \"{}\" not found, tried: {}",
+                name,
+                path.display()
+            ));
+        }
+
+        let file_name = SearchPath::library_file_name(name);
+        let mut tried = Vec::new();
+
+        for dir in self.dirs.iter() {
+            let candidate = dir.join(&file_name);
+
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+
+            tried.push(candidate.display().to_string());
+        }
+
+        if tried.is_empty() {
+            return Err(format!(
+                "Library \"{}\" not found, no search directories configured (set {})",
+                name, SEARCH_PATH_ENV
+            ));
+        }
+
+        Err(format!(
+            "Library \"{}\" not found, tried: {}",
+            name,
+            tried.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_path_resolves_logical_names() {
+        let dir = env::temp_dir().join(format!("ms-search-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let file = dir.join(SearchPath::library_file_name("foo"));
+        std::fs::write(&file, b"").unwrap();
+
+        let search_path = SearchPath::new().add_dir("/nonexistent").add_dir(&dir);
+        assert_eq!(search_path.resolve("foo").unwrap(), file);
+
+        let error = search_path.resolve("bar").unwrap_err();
+        assert!(error.contains("/nonexistent"));
+        assert!(error.contains(&SearchPath::library_file_name("bar")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unresolved_libraries_fall_back_to_the_platform_loader() {
+        assert_eq!(
+            SearchPath::platform_name("foo"),
+            PathBuf::from(SearchPath::library_file_name("foo"))
+        );
+        assert_eq!(
+            SearchPath::platform_name("libfoo.so.1"),
+            PathBuf::from("libfoo.so.1")
+        );
+
+        let code = crate::asm::assemble("(mod.load ms_missing_library (fn.get f))").unwrap();
+        let error = crate::Loader::new()
+            .search_path(SearchPath::new().add_dir("/nonexistent"))
+            .load(&code)
+            .err()
+            .unwrap();
+
+        // Both the search path and the platform loader were tried
+        assert!(error.contains("tried: /nonexistent"));
+        assert!(error.contains(&SearchPath::library_file_name("ms_missing_library")));
+    }
+}
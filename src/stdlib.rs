@@ -0,0 +1,335 @@
+use std::{
+    io::{BufRead, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{NativeModule, Value, VirtualMachine};
+
+impl VirtualMachine {
+    // Register the built-in std.* modules
+    pub fn add_stdlib(&mut self) {
+        for module in modules() {
+            self.add_native_module(module);
+        }
+    }
+}
+
+pub fn modules() -> Vec<NativeModule> {
+    vec![io(), math(), string(), time(), random()]
+}
+
+// Text of a value as printed by scripts, strings without quotes
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => format!("{:?}", value),
+    }
+}
+
+fn expect_args(name: &str, args: &[Value], count: usize) -> Result<(), String> {
+    if args.len() != count {
+        return Err(format!(
+            "{} expects {} arguments, found {}",
+            name,
+            count,
+            args.len()
+        ));
+    }
+
+    Ok(())
+}
+
+fn number(name: &str, value: &Value) -> Result<f32, String> {
+    match value {
+        Value::Integer(value) => Ok(*value as f32),
+        Value::Float(value) => Ok(*value),
+        value => Err(format!("{} expects a number, found {:?}", name, value)),
+    }
+}
+
+fn integer(name: &str, value: &Value) -> Result<i32, String> {
+    match value {
+        Value::Integer(value) => Ok(*value),
+        value => Err(format!("{} expects an integer, found {:?}", name, value)),
+    }
+}
+
+fn string_arg<'a>(name: &str, value: &'a Value) -> Result<&'a str, String> {
+    match value {
+        Value::String(value) => Ok(value),
+        value => Err(format!("{} expects a string, found {:?}", name, value)),
+    }
+}
+
+fn float_fn(
+    name: &'static str,
+    f: fn(f32) -> f32,
+) -> impl FnMut(Vec<Value>) -> Result<Option<Value>, String> {
+    move |args| {
+        expect_args(name, &args, 1)?;
+        Ok(Some(Value::Float(f(number(name, &args[0])?))))
+    }
+}
+
+pub fn io() -> NativeModule {
+    NativeModule::new("std.io")
+        .function("print", |args| {
+            let text: Vec<String> = args.iter().map(display).collect();
+            print!("{}", text.join(" "));
+            std::io::stdout().flush().map_err(|e| e.to_string())?;
+            Ok(None)
+        })
+        .function("println", |args| {
+            let text: Vec<String> = args.iter().map(display).collect();
+            println!("{}", text.join(" "));
+            Ok(None)
+        })
+        .function("read_line", |args| {
+            expect_args("read_line", &args, 0)?;
+
+            let mut line = String::new();
+            std::io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .map_err(|e| e.to_string())?;
+
+            let line = line.trim_end_matches(['\n', '\r']).to_string();
+            Ok(Some(Value::String(line)))
+        })
+}
+
+pub fn math() -> NativeModule {
+    NativeModule::new("std.math")
+        .function("sqrt", float_fn("sqrt", f32::sqrt))
+        .function("sin", float_fn("sin", f32::sin))
+        .function("cos", float_fn("cos", f32::cos))
+        .function("tan", float_fn("tan", f32::tan))
+        .function("floor", float_fn("floor", f32::floor))
+        .function("ceil", float_fn("ceil", f32::ceil))
+        .function("round", float_fn("round", f32::round))
+        .function("pow", |args| {
+            expect_args("pow", &args, 2)?;
+
+            match (&args[0], &args[1]) {
+                (Value::Integer(base), Value::Integer(exp)) if *exp >= 0 => {
+                    Ok(Some(Value::Integer(base.wrapping_pow(*exp as u32))))
+                }
+                (base, exp) => Ok(Some(Value::Float(
+                    number("pow", base)?.powf(number("pow", exp)?),
+                ))),
+            }
+        })
+        .function("abs", |args| {
+            expect_args("abs", &args, 1)?;
+
+            match &args[0] {
+                Value::Integer(value) => Ok(Some(Value::Integer(value.wrapping_abs()))),
+                value => Ok(Some(Value::Float(number("abs", value)?.abs()))),
+            }
+        })
+        .function("min", |args| {
+            expect_args("min", &args, 2)?;
+
+            match (&args[0], &args[1]) {
+                (Value::Integer(a), Value::Integer(b)) => Ok(Some(Value::Integer(*a.min(b)))),
+                (a, b) => Ok(Some(Value::Float(number("min", a)?.min(number("min", b)?)))),
+            }
+        })
+        .function("max", |args| {
+            expect_args("max", &args, 2)?;
+
+            match (&args[0], &args[1]) {
+                (Value::Integer(a), Value::Integer(b)) => Ok(Some(Value::Integer(*a.max(b)))),
+                (a, b) => Ok(Some(Value::Float(number("max", a)?.max(number("max", b)?)))),
+            }
+        })
+}
+
+pub fn string() -> NativeModule {
+    NativeModule::new("std.string")
+        .function("len", |args| {
+            expect_args("len", &args, 1)?;
+            let value = string_arg("len", &args[0])?;
+            Ok(Some(Value::Integer(value.chars().count() as i32)))
+        })
+        .function("upper", |args| {
+            expect_args("upper", &args, 1)?;
+            let value = string_arg("upper", &args[0])?;
+            Ok(Some(Value::String(value.to_uppercase())))
+        })
+        .function("lower", |args| {
+            expect_args("lower", &args, 1)?;
+            let value = string_arg("lower", &args[0])?;
+            Ok(Some(Value::String(value.to_lowercase())))
+        })
+        .function("trim", |args| {
+            expect_args("trim", &args, 1)?;
+            let value = string_arg("trim", &args[0])?;
+            Ok(Some(Value::String(value.trim().to_string())))
+        })
+        .function("contains", |args| {
+            expect_args("contains", &args, 2)?;
+            let value = string_arg("contains", &args[0])?;
+            let pattern = string_arg("contains", &args[1])?;
+            Ok(Some(Value::Boolean(value.contains(pattern))))
+        })
+        .function("substr", |args| {
+            expect_args("substr", &args, 3)?;
+            let value = string_arg("substr", &args[0])?;
+            let start = integer("substr", &args[1])?.max(0) as usize;
+            let len = integer("substr", &args[2])?.max(0) as usize;
+            Ok(Some(Value::String(
+                value.chars().skip(start).take(len).collect(),
+            )))
+        })
+        .function("to_string", |args| {
+            expect_args("to_string", &args, 1)?;
+            Ok(Some(Value::String(display(&args[0]))))
+        })
+        .function("parse_int", |args| {
+            expect_args("parse_int", &args, 1)?;
+            let value = string_arg("parse_int", &args[0])?;
+
+            match value.trim().parse::<i32>() {
+                Ok(value) => Ok(Some(Value::Integer(value))),
+                Err(_) => Ok(Some(Value::Null)),
+            }
+        })
+        .function("parse_float", |args| {
+            expect_args("parse_float", &args, 1)?;
+            let value = string_arg("parse_float", &args[0])?;
+
+            match value.trim().parse::<f32>() {
+                Ok(value) => Ok(Some(Value::Float(value))),
+                Err(_) => Ok(Some(Value::Null)),
+            }
+        })
+}
+
+pub fn time() -> NativeModule {
+    let start = Instant::now();
+
+    NativeModule::new("std.time")
+        .function("now", |args| {
+            expect_args("now", &args, 0)?;
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| e.to_string())?;
+
+            // Seconds since the epoch don't fit an i32 after 2038, at today's dates an f32 is
+            // precise to about two minutes so intervals should be measured with elapsed
+            Ok(Some(Value::Float(now.as_secs_f64() as f32)))
+        })
+        .function("elapsed", move |args| {
+            expect_args("elapsed", &args, 0)?;
+            Ok(Some(Value::Float(start.elapsed().as_secs_f32())))
+        })
+        .function("sleep", |args| {
+            expect_args("sleep", &args, 1)?;
+            let millis = integer("sleep", &args[0])?.max(0) as u64;
+            std::thread::sleep(Duration::from_millis(millis));
+            Ok(None)
+        })
+}
+
+// SplitMix64 generator, small and good enough for scripting
+struct Random {
+    state: u64,
+}
+
+impl Random {
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+pub fn random() -> NativeModule {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+
+    let random = Arc::new(Mutex::new(Random { state: seed }));
+    let (seed_state, int_state, float_state) = (random.clone(), random.clone(), random);
+
+    NativeModule::new("std.random")
+        .function("seed", move |args| {
+            expect_args("seed", &args, 1)?;
+            seed_state.lock().unwrap().state = integer("seed", &args[0])? as u64;
+            Ok(None)
+        })
+        .function("int", move |args| {
+            expect_args("int", &args, 2)?;
+            let min = integer("int", &args[0])?;
+            let max = integer("int", &args[1])?;
+
+            if max <= min {
+                return Err(format!("int expects min < max, found {} and {}", min, max));
+            }
+
+            let range = (max as i64 - min as i64) as u64;
+            let value = int_state.lock().unwrap().next_u64() % range;
+            Ok(Some(Value::Integer((min as i64 + value as i64) as i32)))
+        })
+        .function("float", move |args| {
+            expect_args("float", &args, 0)?;
+            let value = float_state.lock().unwrap().next_u64() >> 40;
+            Ok(Some(Value::Float(value as f32 / (1u64 << 24) as f32)))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, load_modules};
+
+    fn run(source: &str) -> VirtualMachine {
+        let (modules, _) = load_modules(&assemble(source).unwrap()).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.add_stdlib();
+
+        vm.add_modules(modules).unwrap();
+
+        vm.call("main", "run", vec![]);
+        vm
+    }
+
+    #[test]
+    fn stdlib_math_and_string() {
+        let vm = run(r#"
+            (mod main
+                (fn run
+                    (i32.const 2) (i32.const 10) (call std.math pow 2)
+                    (f32.const 2.5) (call std.math floor 1)
+                    (str.const "abc") (call std.string upper 1)
+                    (str.const "hello") (i32.const 1) (i32.const 3) (call std.string substr 3)))
+            "#);
+
+        assert_eq!(format!("{:?}", vm.stack), "[1024, 2, \"ABC\", \"ell\"]");
+    }
+
+    #[test]
+    fn stdlib_random_is_seedable() {
+        let source = r#"
+            (mod main
+                (fn run
+                    (i32.const 42) (call std.random seed 1)
+                    (i32.const 0) (i32.const 100) (call std.random int 2)
+                    (i32.const 0) (i32.const 100) (call std.random int 2)))
+            "#;
+
+        let a = run(source);
+        let b = run(source);
+        let c = run(&source.replace("(i32.const 42)", "(i32.const 43)"));
+
+        assert_eq!(format!("{:?}", a.stack), format!("{:?}", b.stack));
+        assert_ne!(format!("{:?}", a.stack), format!("{:?}", c.stack));
+    }
+}
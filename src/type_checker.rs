@@ -0,0 +1,870 @@
+use std::collections::HashMap;
+
+use crate::{
+    verifier::Location, Capture, Code, Constant, Diagnostic, Instruction, Signature, Value,
+};
+
+// Static type of a value, Any matches every type
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Type {
+    Any = 0,
+    Null = 1,
+    Boolean = 2,
+    Integer = 3,
+    Float = 4,
+    String = 5,
+    Object = 6,
+    Closure = 7,
+}
+
+impl Type {
+    // Type names used by the assembler
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "any" => Some(Type::Any),
+            "null" => Some(Type::Null),
+            "bool" => Some(Type::Boolean),
+            "i32" => Some(Type::Integer),
+            "f32" => Some(Type::Float),
+            "str" => Some(Type::String),
+            "obj" => Some(Type::Object),
+            "closure" => Some(Type::Closure),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Type::Any => "any",
+            Type::Null => "null",
+            Type::Boolean => "bool",
+            Type::Integer => "i32",
+            Type::Float => "f32",
+            Type::String => "str",
+            Type::Object => "obj",
+            Type::Closure => "closure",
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Type> {
+        match value {
+            0 => Some(Type::Any),
+            1 => Some(Type::Null),
+            2 => Some(Type::Boolean),
+            3 => Some(Type::Integer),
+            4 => Some(Type::Float),
+            5 => Some(Type::String),
+            6 => Some(Type::Object),
+            7 => Some(Type::Closure),
+            _ => None,
+        }
+    }
+
+    pub fn of(value: &Value) -> Type {
+        match value {
+            Value::Null => Type::Null,
+            Value::Boolean(_) => Type::Boolean,
+            Value::Integer(_) => Type::Integer,
+            Value::Float(_) => Type::Float,
+            Value::String(_) => Type::String,
+            Value::Object(_) => Type::Object,
+            Value::Closure(_) => Type::Closure,
+        }
+    }
+
+    pub fn accepts(&self, other: Type) -> bool {
+        *self == Type::Any || other == Type::Any || *self == other
+    }
+
+    // Initial value of a typed local
+    pub fn default_value(&self) -> Value {
+        match self {
+            Type::Boolean => Value::Boolean(false),
+            Type::Integer => Value::Integer(0),
+            Type::Float => Value::Float(0.0),
+            Type::String => Value::String(String::new()),
+            _ => Value::Null,
+        }
+    }
+}
+
+impl From<&Constant> for Type {
+    fn from(value: &Constant) -> Self {
+        match value {
+            Constant::String(_) => Type::String,
+            Constant::Integer(_) => Type::Integer,
+            Constant::Float(_) => Type::Float,
+            Constant::Boolean(_) => Type::Boolean,
+        }
+    }
+}
+
+// Result of type checking a program
+#[derive(Debug, Clone, Default)]
+pub struct TypeReport {
+    pub diagnostics: Vec<Diagnostic>,
+    pub checked: Vec<(String, String)>, // Typed functions whose whole body could be checked
+}
+
+// Infer the types on the operand stack through every function with a typed signature,
+// untyped functions are left to the dynamic checks of the virtual machine. Calls made by checked
+// functions skip the argument checks of the virtual machine, operators keep their checks
+pub fn type_check(code: &Code) -> TypeReport {
+    let mut signatures = HashMap::new();
+    let mut trusted = Vec::new();
+
+    for instruction in code.iter() {
+        if let Instruction::Module { name, code } = instruction {
+            for instruction in code.iter() {
+                if let Instruction::Fn {
+                    name: function,
+                    signature: Some(signature),
+                    ..
+                } = instruction
+                {
+                    signatures.insert((name.as_str(), function.as_str()), signature);
+
+                    if signature.is_typed() {
+                        trusted.push((name.clone(), function.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    // Results are only trusted when the callee is checked too, start by trusting every typed
+    // function and drop the ones that can't be checked until nothing changes
+    loop {
+        let mut checker = Checker {
+            signatures: &signatures,
+            trusted: &trusted,
+            report: TypeReport::default(),
+        };
+        checker.program(code);
+
+        if checker.report.checked == trusted {
+            return checker.report;
+        }
+
+        trusted = checker.report.checked;
+    }
+}
+
+// Types on the operand stack, None once unreachable or unknown
+type Stack = Option<Vec<Type>>;
+
+struct Checker<'a> {
+    signatures: &'a HashMap<(&'a str, &'a str), &'a Signature>,
+    trusted: &'a [(String, String)], // Functions whose declared result types can be relied on
+    report: TypeReport,
+}
+
+struct Frame<'a> {
+    location: Location,
+    locals: Vec<Type>,
+    results: Vec<Type>,
+    constants: &'a [Type],
+    loops: Vec<Exits>, // Enclosing loops, innermost last
+    complete: bool,    // False when types were lost, for example after an untyped call
+}
+
+// Stacks at the break and continue instructions of a loop
+#[derive(Default)]
+struct Exits {
+    breaks: Vec<Stack>,
+    continues: Vec<Stack>,
+}
+
+impl<'a> Checker<'a> {
+    fn program(&mut self, code: &Code) {
+        for instruction in code.iter() {
+            if let Instruction::Module { name, code } = instruction {
+                let constants: Vec<Type> = code
+                    .iter()
+                    .filter_map(|i| match i {
+                        Instruction::Constants { values } => Some(values),
+                        _ => None,
+                    })
+                    .flatten()
+                    .map(Type::from)
+                    .collect();
+
+                for instruction in code.iter() {
+                    let Instruction::Fn {
+                        name: function,
+                        signature: Some(signature),
+                        code,
+                    } = instruction
+                    else {
+                        continue;
+                    };
+
+                    if !signature.is_typed() {
+                        continue;
+                    }
+
+                    let mut locals: Vec<Type> = signature
+                        .param_types
+                        .iter()
+                        .chain(signature.local_types.iter())
+                        .copied()
+                        .collect();
+                    forget_cells(code, &mut locals);
+
+                    let mut frame = Frame {
+                        location: Location::new(name, function),
+                        locals,
+                        results: signature.result_types.clone(),
+                        constants: &constants,
+                        loops: Vec::new(),
+                        complete: true,
+                    };
+
+                    let end = self.block(code, Some(Vec::new()), &mut frame);
+                    self.check_results(&end, &mut frame);
+
+                    if frame.complete {
+                        self.report.checked.push((name.clone(), function.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn error(&mut self, frame: &Frame, message: String) {
+        self.report
+            .diagnostics
+            .push(frame.location.diagnostic(message));
+    }
+
+    // Whether a value can be stored where a type is declared, values of unknown type are
+    // accepted but keep the frame from being checked since nothing else would check them
+    fn accepts(expected: Type, found: Type, frame: &mut Frame) -> bool {
+        if found == Type::Any && expected != Type::Any {
+            frame.complete = false;
+        }
+
+        expected.accepts(found)
+    }
+
+    fn check_results(&mut self, stack: &Stack, frame: &mut Frame) {
+        let Some(stack) = stack else {
+            return;
+        };
+
+        if stack.len() != frame.results.len() {
+            return; // Reported by the verifier
+        }
+
+        for (index, (found, expected)) in stack.iter().zip(frame.results.clone()).enumerate() {
+            if !Self::accepts(expected, *found, frame) {
+                self.error(
+                    frame,
+                    format!(
+                        "result {} is {}, declared {}",
+                        index,
+                        found.name(),
+                        expected.name()
+                    ),
+                );
+            }
+        }
+    }
+
+    // Pop count types, losing track of the stack when it is too short
+    fn pop(&mut self, stack: &mut Stack, count: usize, frame: &mut Frame) -> Option<Vec<Type>> {
+        let values = stack.as_mut()?;
+
+        if values.len() < count {
+            frame.complete = false;
+            *stack = None;
+            return None;
+        }
+
+        Some(values.split_off(values.len() - count))
+    }
+
+    fn push(stack: &mut Stack, types: &[Type]) {
+        if let Some(stack) = stack {
+            stack.extend_from_slice(types);
+        }
+    }
+
+    fn operands(&mut self, name: &str, a: Type, b: Type, allowed: &[Type], frame: &Frame) -> Type {
+        for value in [a, b] {
+            if value != Type::Any && !allowed.contains(&value) {
+                self.error(frame, format!("{} on {}", name, value.name()));
+                return Type::Any;
+            }
+        }
+
+        if !a.accepts(b) {
+            self.error(frame, format!("{} on {} and {}", name, b.name(), a.name()));
+            return Type::Any;
+        }
+
+        if a == Type::Any {
+            b
+        } else {
+            a
+        }
+    }
+
+    fn block(&mut self, code: &Code, mut stack: Stack, frame: &mut Frame) -> Stack {
+        const NUMBERS: &[Type] = &[Type::Integer, Type::Float];
+        const ADDABLE: &[Type] = &[Type::Integer, Type::Float, Type::String];
+        const COMPARABLE: &[Type] = &[Type::Integer, Type::Float, Type::String, Type::Boolean];
+
+        for instruction in code.iter() {
+            if stack.is_none() {
+                break;
+            }
+
+            frame.location.index += 1;
+
+            match instruction {
+                Instruction::PushConstString { .. } => Self::push(&mut stack, &[Type::String]),
+                Instruction::PushConstInteger { .. } => Self::push(&mut stack, &[Type::Integer]),
+                Instruction::PushConstFloat { .. } => Self::push(&mut stack, &[Type::Float]),
+                Instruction::PushConstBoolean { .. } => Self::push(&mut stack, &[Type::Boolean]),
+                Instruction::GetConst { index } => {
+                    let value = frame
+                        .constants
+                        .get(*index as usize)
+                        .copied()
+                        .unwrap_or(Type::Any);
+                    Self::push(&mut stack, &[value]);
+                }
+                Instruction::GetLocal { index } => {
+                    let value = frame.locals.get(*index as usize).copied();
+                    Self::push(&mut stack, &[value.unwrap_or(Type::Any)]);
+                }
+                Instruction::SetLocal { index } => {
+                    let Some(value) = self.pop(&mut stack, 1, frame) else {
+                        break;
+                    };
+
+                    if let Some(local) = frame.locals.get(*index as usize).copied() {
+                        if !Self::accepts(local, value[0], frame) {
+                            self.error(
+                                frame,
+                                format!(
+                                    "local {} is {}, found {}",
+                                    index,
+                                    local.name(),
+                                    value[0].name()
+                                ),
+                            );
+                        }
+                    }
+                }
+                Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div => {
+                    let Some(values) = self.pop(&mut stack, 2, frame) else {
+                        break;
+                    };
+
+                    let (name, allowed) = match instruction {
+                        Instruction::Add => ("op.add", ADDABLE),
+                        Instruction::Sub => ("op.sub", NUMBERS),
+                        Instruction::Mul => ("op.mul", NUMBERS),
+                        _ => ("op.div", NUMBERS),
+                    };
+
+                    let result = self.operands(name, values[1], values[0], allowed, frame);
+                    Self::push(&mut stack, &[result]);
+                }
+                Instruction::Inc | Instruction::Dec => {
+                    let Some(values) = self.pop(&mut stack, 1, frame) else {
+                        break;
+                    };
+
+                    let name = match instruction {
+                        Instruction::Inc => "op.inc",
+                        _ => "op.dec",
+                    };
+
+                    let result = self.operands(name, values[0], values[0], NUMBERS, frame);
+                    Self::push(&mut stack, &[result]);
+                }
+                Instruction::Eq
+                | Instruction::Ne
+                | Instruction::Lt
+                | Instruction::Le
+                | Instruction::Gt
+                | Instruction::Ge => {
+                    let Some(values) = self.pop(&mut stack, 2, frame) else {
+                        break;
+                    };
+
+                    let allowed = match instruction {
+                        Instruction::Eq | Instruction::Ne => COMPARABLE,
+                        _ => NUMBERS,
+                    };
+
+                    self.operands("comparison", values[1], values[0], allowed, frame);
+                    Self::push(&mut stack, &[Type::Boolean]);
+                }
+                Instruction::Call {
+                    module,
+                    function,
+                    param_count,
+                }
+                | Instruction::TailCall {
+                    module,
+                    function,
+                    param_count,
+                } => {
+                    let Some(args) = self.pop(&mut stack, *param_count as usize, frame) else {
+                        break;
+                    };
+
+                    let Some(signature) = self
+                        .signatures
+                        .get(&(module.as_str(), function.as_str()))
+                        .copied()
+                    else {
+                        // Host functions and untyped calls leave unknown values
+                        frame.complete = false;
+                        stack = None;
+                        break;
+                    };
+
+                    for (index, (found, expected)) in
+                        args.iter().zip(signature.param_types.iter()).enumerate()
+                    {
+                        if !Self::accepts(*expected, *found, frame) {
+                            self.error(
+                                frame,
+                                format!(
+                                    "{}.{} argument {} expects {}, found {}",
+                                    module,
+                                    function,
+                                    index,
+                                    expected.name(),
+                                    found.name()
+                                ),
+                            );
+                        }
+                    }
+
+                    let trusted = self
+                        .trusted
+                        .iter()
+                        .any(|(m, f)| m == module && f == function);
+
+                    let mut results = if trusted {
+                        signature.result_types.clone()
+                    } else {
+                        Vec::new()
+                    };
+                    results.resize(signature.results as usize, Type::Any);
+                    Self::push(&mut stack, &results);
+
+                    if let Instruction::TailCall { .. } = instruction {
+                        self.check_results(&stack, frame);
+                        stack = None;
+                    }
+                }
+                Instruction::CallClosure { .. } | Instruction::CallMethod { .. } => {
+                    frame.complete = false;
+                    stack = None;
+                }
+                Instruction::MakeClosure { .. } => Self::push(&mut stack, &[Type::Closure]),
+                Instruction::Allocate { .. } | Instruction::New { .. } => {
+                    Self::push(&mut stack, &[Type::Object])
+                }
+                Instruction::GetUpvalue { .. } | Instruction::GetGlobal { .. } => {
+                    Self::push(&mut stack, &[Type::Any])
+                }
+                Instruction::SetUpvalue { .. } | Instruction::SetGlobal { .. } => {
+                    self.pop(&mut stack, 1, frame);
+                }
+                Instruction::IsInstance { .. } => {
+                    self.pop(&mut stack, 1, frame);
+                    Self::push(&mut stack, &[Type::Boolean]);
+                }
+                Instruction::GetField { .. } | Instruction::GetFieldByName { .. } => {
+                    self.pop(&mut stack, 1, frame);
+                    Self::push(&mut stack, &[Type::Any]);
+                }
+                Instruction::SetField { .. } | Instruction::SetFieldByName { .. } => {
+                    self.pop(&mut stack, 1, frame);
+                }
+                Instruction::Pop => {
+                    self.pop(&mut stack, 1, frame);
+                }
+                Instruction::Drop { count } => {
+                    self.pop(&mut stack, *count as usize, frame);
+                }
+                Instruction::Dup | Instruction::DupN { .. } => {
+                    let count = match instruction {
+                        Instruction::DupN { count } => *count as usize,
+                        _ => 1,
+                    };
+
+                    if let Some(values) = self.pop(&mut stack, count, frame) {
+                        Self::push(&mut stack, &values);
+                        Self::push(&mut stack, &values);
+                    }
+                }
+                Instruction::Swap => {
+                    if let Some(values) = self.pop(&mut stack, 2, frame) {
+                        Self::push(&mut stack, &[values[1], values[0]]);
+                    }
+                }
+                Instruction::Over => {
+                    if let Some(values) = self.pop(&mut stack, 2, frame) {
+                        Self::push(&mut stack, &[values[0], values[1], values[0]]);
+                    }
+                }
+                Instruction::Rot => {
+                    if let Some(values) = self.pop(&mut stack, 3, frame) {
+                        Self::push(&mut stack, &[values[1], values[2], values[0]]);
+                    }
+                }
+                Instruction::Pick { depth } => {
+                    // The stack never holds that many values, too large depths fail to pop
+                    let count = (*depth as usize).saturating_add(1);
+
+                    if let Some(values) = self.pop(&mut stack, count, frame) {
+                        Self::push(&mut stack, &values);
+                        Self::push(&mut stack, &values[..1]);
+                    }
+                }
+                Instruction::Return => {
+                    self.check_results(&stack, frame);
+                    stack = None;
+                }
+                Instruction::Break { depth } | Instruction::Continue { depth } => {
+                    // Out of range depths are reported by the verifier
+                    let target = (*depth as usize)
+                        .checked_add(1)
+                        .and_then(|depth| frame.loops.len().checked_sub(depth));
+
+                    if let Some(exits) = target.map(|index| &mut frame.loops[index]) {
+                        match instruction {
+                            Instruction::Break { .. } => exits.breaks.push(stack.clone()),
+                            _ => exits.continues.push(stack.clone()),
+                        }
+                    }
+
+                    stack = None;
+                }
+                Instruction::Then {
+                    then_block,
+                    else_block,
+                } => {
+                    if let Some(condition) = self.pop(&mut stack, 1, frame) {
+                        if !Type::Boolean.accepts(condition[0]) {
+                            self.error(
+                                frame,
+                                format!("condition is {}, expected bool", condition[0].name()),
+                            );
+                        }
+                    }
+
+                    let then_stack = self.block(then_block, stack.clone(), frame);
+                    let else_stack = self.block(else_block, stack.clone(), frame);
+                    stack = Self::merge(&[then_stack, else_stack], frame);
+                }
+                Instruction::Switch { cases, default } => {
+                    if let Some(selector) = self.pop(&mut stack, 1, frame) {
+                        if !Type::Integer.accepts(selector[0]) {
+                            self.error(
+                                frame,
+                                format!("switch on {}, expected i32", selector[0].name()),
+                            );
+                        }
+                    }
+
+                    let mut stacks: Vec<Stack> = cases
+                        .iter()
+                        .map(|case| self.block(case, stack.clone(), frame))
+                        .collect();
+                    stacks.push(self.block(default, stack.clone(), frame));
+                    stack = Self::merge(&stacks, frame);
+                }
+                Instruction::Loop { block } => {
+                    // The body runs again with the stack it ends or continues with, check it
+                    // until the types at its start stop changing and keep the last diagnostics
+                    let index = frame.location.index;
+                    let diagnostics = self.report.diagnostics.len();
+                    let mut entry = stack.clone();
+
+                    let exits = loop {
+                        frame.location.index = index;
+                        self.report.diagnostics.truncate(diagnostics);
+
+                        frame.loops.push(Exits::default());
+                        let end = self.block(block, entry.clone(), frame);
+                        let exits = frame.loops.pop().unwrap_or_default();
+
+                        let mut stacks = vec![entry.clone(), end];
+                        stacks.extend(exits.continues.iter().cloned());
+                        let next = Self::merge(&stacks, frame);
+
+                        if next.is_none() || next == entry {
+                            break exits;
+                        }
+
+                        entry = next;
+                    };
+
+                    // Loops are only left by break
+                    stack = Self::merge(&exits.breaks, frame);
+                }
+                Instruction::None
+                | Instruction::Dump
+                | Instruction::Hi
+                | Instruction::ReserveLocal { .. } => {}
+                // Declarations are rejected by the verifier
+                _ => {}
+            }
+        }
+
+        stack
+    }
+
+    // Stack after branches, a type that differs between branches becomes Any
+    fn merge(stacks: &[Stack], frame: &mut Frame) -> Stack {
+        let mut reachable = stacks.iter().flatten();
+
+        let mut merged = reachable.next()?.clone();
+
+        for stack in reachable {
+            if stack.len() != merged.len() {
+                frame.complete = false;
+                return None;
+            }
+
+            for (merged, value) in merged.iter_mut().zip(stack.iter()) {
+                if merged != value {
+                    *merged = Type::Any;
+                }
+            }
+        }
+
+        Some(merged)
+    }
+}
+
+// Locals captured as cells can be set to any type by the closures sharing them
+fn forget_cells(code: &Code, locals: &mut [Type]) {
+    for instruction in code.iter() {
+        match instruction {
+            Instruction::MakeClosure { captures, .. } => {
+                for capture in captures.iter() {
+                    if let Capture::Cell(index) = capture {
+                        if let Some(local) = locals.get_mut(*index as usize) {
+                            *local = Type::Any;
+                        }
+                    }
+                }
+            }
+            Instruction::Loop { block } => forget_cells(block, locals),
+            Instruction::Then {
+                then_block,
+                else_block,
+            } => {
+                forget_cells(then_block, locals);
+                forget_cells(else_block, locals);
+            }
+            Instruction::Switch { cases, default } => {
+                for case in cases.iter() {
+                    forget_cells(case, locals);
+                }
+
+                forget_cells(default, locals);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, load_modules, parser::Parser, Loader, VirtualMachine};
+
+    fn check(source: &str) -> TypeReport {
+        let code = Instruction::from_sexprs(&Parser::new(source).parse().unwrap()).unwrap();
+        type_check(&code)
+    }
+
+    #[test]
+    fn type_check_flags_mismatched_operands() {
+        let report = check(
+            r#"
+            (mod main
+                (fn add (params i32 f32) (results i32) (locals str)
+                    (local.get 0) (local.get 1) (op.add)
+                    (local.get 0) (local.set 2)
+                    (str.const "a") (i32.const 1) (cmp.lt) (pop))
+                (fn run (results i32)
+                    (i32.const 1) (i32.const 2) (call main add 2)))
+            "#,
+        );
+
+        let diagnostics: Vec<String> = report.diagnostics.iter().map(|d| d.to_string()).collect();
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                "main.add@3: op.add on i32 and f32",
+                "main.add@5: local 2 is str, found i32",
+                "main.add@8: comparison on str",
+                "main.run@3: main.add argument 1 expects f32, found i32",
+            ]
+        );
+        // Operands that don't match leave values of unknown type, add returns one as i32
+        assert!(report.checked.is_empty());
+        assert!(assemble("(mod main (fn f (results i32) (str.const \"a\")))").is_err());
+    }
+
+    #[test]
+    fn verified_code_skips_argument_checks() {
+        let source = r#"
+            (mod main
+                (fn square (params i32) (results i32) (locals i32)
+                    (local.get 0) (local.get 0) (op.mul) (local.set 1) (local.get 1))
+                (fn run (results i32) (i32.const 7) (call main square 1)))
+            "#;
+
+        let code = assemble(source).unwrap();
+        let (modules, _) = Loader::new().verify(true).load(&code).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.add_modules(modules).unwrap();
+
+        assert!(vm.get_function("main", "run").unwrap().verified);
+
+        vm.call("main", "run", vec![]);
+        assert_eq!(format!("{:?}", vm.stack), "[49]");
+
+        let (modules, _) = load_modules(&code).unwrap();
+        assert!(!modules[0].get_function("run").unwrap().verified);
+    }
+
+    #[test]
+    #[should_panic(expected = "Function \"main.square\" argument 0 expects i32, found \"a\"")]
+    fn unverified_calls_check_argument_types() {
+        let mut vm = VirtualMachine::new();
+        let (modules, _) = load_modules(
+            &assemble("(mod main (fn square (params i32) (results i32) (local.get 0)))").unwrap(),
+        )
+        .unwrap();
+        vm.add_modules(modules).unwrap();
+
+        vm.call("main", "square", vec![Value::String("a".to_string())]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Function \"main.square\" argument 0 expects i32, found \"a\"")]
+    fn values_of_unknown_type_keep_argument_checks() {
+        let source = r#"
+            (mod main
+                (global name (str.const "a"))
+                (fn square (params i32) (results i32) (local.get 0) (local.get 0) (op.mul))
+                (fn twice (params i32) (results i32) (local.get 0) (call main square 1))
+                (fn run (results i32) (global.get name) (call main square 1))
+                (fn wrap (results i32) (call main run 0)))
+            "#;
+
+        let code = assemble(source).unwrap();
+        let checked: Vec<String> = type_check(&code)
+            .checked
+            .iter()
+            .map(|(_, function)| function.clone())
+            .collect();
+        assert_eq!(checked, vec!["square", "twice"]);
+
+        let (modules, _) = Loader::new().verify(true).load(&code).unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.add_modules(modules).unwrap();
+
+        vm.call("main", "wrap", vec![]);
+    }
+
+    #[test]
+    fn type_check_survives_operands_out_of_range() {
+        let mut code = assemble("(mod main (fn run (results i32) (i32.const 1)))").unwrap();
+        let Instruction::Module { code: module, .. } = &mut code[1] else {
+            unreachable!("expected (mod)");
+        };
+        let Some(Instruction::Fn { code: function, .. }) = module
+            .iter_mut()
+            .find(|instruction| matches!(instruction, Instruction::Fn { .. }))
+        else {
+            unreachable!("expected (fn)");
+        };
+        function.push(Instruction::Pick { depth: u32::MAX });
+
+        assert!(type_check(&code).checked.is_empty());
+    }
+
+    #[test]
+    fn loops_are_checked_with_the_stack_of_every_iteration() {
+        let checked = |source: &str| {
+            let report = type_check(&assemble(source).unwrap());
+            assert!(report.diagnostics.is_empty(), "{:?}", report.diagnostics);
+            report.checked
+        };
+
+        // The second iteration passes the string pushed by the first one
+        let grows = checked(
+            r#"
+            (mod main
+                (fn g (params i32))
+                (fn run (params i32)
+                    (local.get 0) (loop (dup) (call main g 1) (str.const "a"))))
+            "#,
+        );
+        let changes = checked(
+            r#"
+            (mod main
+                (fn g (params i32))
+                (fn run (params i32)
+                    (local.get 0) (loop (dup) (call main g 1) (pop) (str.const "a"))))
+            "#,
+        );
+
+        assert_eq!(grows, vec![("main".to_string(), "g".to_string())]);
+        assert_eq!(changes, vec![("main".to_string(), "g".to_string())]);
+    }
+
+    #[test]
+    fn loops_leave_with_the_stack_at_break() {
+        let report = check(
+            r#"
+            (mod main
+                (fn run (params i32) (results i32)
+                    (local.get 0) (loop (pop) (str.const "a") (break))))
+            "#,
+        );
+
+        let diagnostics: Vec<String> = report.diagnostics.iter().map(|d| d.to_string()).collect();
+
+        assert_eq!(
+            diagnostics,
+            vec!["main.run@5: result 0 is str, declared i32"]
+        );
+    }
+
+    #[test]
+    fn locals_captured_as_cells_lose_their_type() {
+        let report = type_check(
+            &assemble(
+                r#"
+                (mod main
+                    (fn set (str.const "a") (upval.set 0))
+                    (fn run (params i32) (results i32)
+                        (closure main set (cell 0)) (call.closure 0) (local.get 0)))
+                "#,
+            )
+            .unwrap(),
+        );
+
+        assert!(report.diagnostics.is_empty());
+        assert!(report.checked.is_empty());
+    }
+}
@@ -1,11 +1,16 @@
 use std::{
+    any::Any,
     fmt::Debug,
+    ops::{Deref, DerefMut},
     sync::{Arc, Mutex},
 };
 
+use crate::Constant;
+
 pub enum Object {
     Values(Vec<Value>),
-    Native(Box<dyn NativeObject>),
+    Struct { name: String, fields: Vec<Value> },
+    Native(NativeBox),
 }
 
 #[derive(Clone)]
@@ -16,14 +21,166 @@ pub enum Value {
     Float(f32),
     String(String),
     Object(Arc<Mutex<Object>>),
+    Closure(Arc<Closure>),
+}
+
+// A function reference together with the values it captured when it was created
+pub struct Closure {
+    pub module: String,
+    pub function: String,
+    pub upvalues: Vec<Arc<Mutex<Value>>>,
+}
+
+// Host object exposed to scripts, every method has a default so hosts only implement what they need
+pub trait NativeObject: Any {
+    fn type_name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    fn get_field(&self, index: u32) -> Option<Value> {
+        let _ = index;
+        None
+    }
+
+    fn set_field(&mut self, index: u32, value: Value) -> Result<(), String> {
+        let _ = value;
+        Err(format!("{} has no field {}", self.type_name(), index))
+    }
+
+    fn get_field_by_name(&self, name: &str) -> Option<Value> {
+        let _ = name;
+        None
+    }
+
+    fn set_field_by_name(&mut self, name: &str, value: Value) -> Result<(), String> {
+        let _ = value;
+        Err(format!("{} has no field {}", self.type_name(), name))
+    }
+
+    fn call_method(&mut self, name: &str, args: Vec<Value>) -> Result<Option<Value>, String> {
+        let _ = args;
+        Err(format!("{} has no method {}", self.type_name(), name))
+    }
+
+    fn fmt_debug(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.type_name())
+    }
+
+    // Called when the last reference to the object is released
+    fn on_drop(&mut self) {}
 }
 
-pub trait NativeObject {}
+impl dyn NativeObject {
+    pub fn downcast_ref<T: NativeObject>(&self) -> Option<&T> {
+        (self as &dyn Any).downcast_ref::<T>()
+    }
+
+    pub fn downcast_mut<T: NativeObject>(&mut self) -> Option<&mut T> {
+        (self as &mut dyn Any).downcast_mut::<T>()
+    }
+}
+
+impl Object {
+    pub fn type_name(&self) -> &str {
+        match self {
+            Object::Values(_) => "Object",
+            Object::Struct { name, .. } => name,
+            Object::Native(native) => native.type_name(),
+        }
+    }
+
+    pub fn downcast_ref<T: NativeObject>(&self) -> Option<&T> {
+        match self {
+            Object::Native(native) => native.downcast_ref::<T>(),
+            _ => None,
+        }
+    }
+
+    pub fn downcast_mut<T: NativeObject>(&mut self) -> Option<&mut T> {
+        match self {
+            Object::Native(native) => native.downcast_mut::<T>(),
+            _ => None,
+        }
+    }
+}
+
+// Owns a native object and runs its drop hook once the last reference is released
+pub struct NativeBox(Box<dyn NativeObject>);
+
+impl NativeBox {
+    pub fn new<T: NativeObject>(object: T) -> NativeBox {
+        NativeBox(Box::new(object))
+    }
+}
+
+impl From<Box<dyn NativeObject>> for NativeBox {
+    fn from(object: Box<dyn NativeObject>) -> Self {
+        NativeBox(object)
+    }
+}
+
+impl Deref for NativeBox {
+    type Target = dyn NativeObject;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl DerefMut for NativeBox {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.0
+    }
+}
+
+impl Drop for NativeBox {
+    fn drop(&mut self) {
+        self.0.on_drop();
+    }
+}
+
+// Values are shared between the frames of a single virtual machine and never cross threads, so
+// what they hold doesn't have to be Send
+impl Value {
+    pub fn native<T: NativeObject>(object: T) -> Value {
+        Value::object(Object::Native(NativeBox::new(object)))
+    }
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub(crate) fn object(object: Object) -> Value {
+        Value::Object(Arc::new(Mutex::new(object)))
+    }
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub(crate) fn closure(closure: Closure) -> Value {
+        Value::Closure(Arc::new(closure))
+    }
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub(crate) fn cell(self) -> Arc<Mutex<Value>> {
+        Arc::new(Mutex::new(self))
+    }
+}
+
+impl From<&Constant> for Value {
+    fn from(constant: &Constant) -> Self {
+        match constant {
+            Constant::String(value) => Value::String(value.clone()),
+            Constant::Integer(value) => Value::Integer(*value),
+            Constant::Float(value) => Value::Float(*value),
+            Constant::Boolean(value) => Value::Boolean(*value),
+        }
+    }
+}
 
 impl Debug for Object {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Object::Values(values) => {
+            Object::Values(values) | Object::Struct { fields: values, .. } => {
+                if let Object::Struct { name, .. } = self {
+                    write!(f, "{}", name)?;
+                }
+
                 write!(f, "[")?;
 
                 let mut it = values.iter();
@@ -38,7 +195,7 @@ impl Debug for Object {
 
                 write!(f, "]")
             }
-            Object::Native(_) => write!(f, "Native"),
+            Object::Native(native) => native.fmt_debug(f),
         }
     }
 }
@@ -53,7 +210,14 @@ impl Debug for Value {
             Value::String(s) => write!(f, "\"{}\"", s),
             Value::Object(arc) => {
                 let obj = arc.lock().unwrap();
-                write!(f, "Object{:?}", obj)
+
+                match &*obj {
+                    Object::Values(_) => write!(f, "Object{:?}", obj),
+                    _ => write!(f, "{:?}", obj),
+                }
+            }
+            Value::Closure(closure) => {
+                write!(f, "Closure({}.{})", closure.module, closure.function)
             }
         }
     }
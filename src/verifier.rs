@@ -0,0 +1,713 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{Capture, Code, Instruction, Signature};
+
+// Problem found by the verifier, location is module.function@instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+// Instruction being checked, shared by the verifier and the type checker
+pub(crate) struct Location {
+    function: String, // module.function or module.global
+    pub index: usize, // Instructions visited so far
+}
+
+impl Location {
+    pub fn new(module: &str, function: &str) -> Location {
+        Location {
+            function: format!("{}.{}", module, function),
+            index: 0,
+        }
+    }
+
+    pub fn diagnostic(&self, message: String) -> Diagnostic {
+        self.diagnostic_at(self.index, message)
+    }
+
+    // Problem of an earlier instruction, blocks are reported at the instruction opening them
+    pub fn diagnostic_at(&self, index: usize, message: String) -> Diagnostic {
+        Diagnostic {
+            location: format!("{}@{}", self.function, index),
+            message,
+        }
+    }
+}
+
+// Check a program before running it, an empty list means the program is well formed
+pub fn verify(code: &Code) -> Vec<Diagnostic> {
+    let mut verifier = Verifier {
+        signatures: HashMap::new(),
+        arguments: HashMap::new(),
+        diagnostics: Vec::new(),
+    };
+
+    // Signatures of every function of the program, calls are checked against them
+    for instruction in code.iter() {
+        if let Instruction::Module { name, code } = instruction {
+            for instruction in code.iter() {
+                if let Instruction::Fn {
+                    name: function,
+                    signature: Some(signature),
+                    ..
+                } = instruction
+                {
+                    verifier
+                        .signatures
+                        .insert((name.clone(), function.clone()), signature.clone());
+                }
+            }
+
+            visit_calls(code, &mut |target, function, arguments| {
+                let entry = verifier
+                    .arguments
+                    .entry((target.to_string(), function.to_string()))
+                    .or_insert(arguments);
+                *entry = (*entry).min(arguments);
+            });
+        }
+    }
+
+    for (index, instruction) in code.iter().enumerate() {
+        match instruction {
+            Instruction::Version { .. } if index == 0 => {}
+            Instruction::Module { name, code } => verifier.module(name, code),
+            Instruction::LoadModule { name, code } => {
+                for instruction in code.iter() {
+                    if !matches!(instruction, Instruction::GetFunction { .. }) {
+                        verifier.report(name, "only (fn.get) is allowed in (mod.load)");
+                    }
+                }
+            }
+            _ => verifier.report(
+                &format!("@{}", index),
+                "only (mod) and (mod.load) are allowed at the top level",
+            ),
+        }
+    }
+
+    verifier.diagnostics
+}
+
+struct Verifier {
+    signatures: HashMap<(String, String), Signature>,
+    // Fewest arguments any call of the program passes to a function, None once a closure of it
+    // is made since closures can be called with any number of arguments
+    arguments: HashMap<(String, String), Option<u32>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// Operand stack depth, None when it depends on calls without a signature
+#[derive(Clone, Copy)]
+struct State {
+    depth: Option<u32>,
+    reachable: bool, // False after return, break and continue
+}
+
+struct Frame {
+    location: Location,
+    locals: Option<u32>,     // Size of the frame, None when arguments are unknown
+    results: Option<u32>,    // Declared results of the function
+    loops: Vec<Option<u32>>, // Stack depth when entering each enclosing loop
+    globals: usize,          // Globals of the module
+    constants: usize,        // Entries of the module constant pool
+}
+
+impl Verifier {
+    fn report(&mut self, location: &str, message: &str) {
+        self.diagnostics.push(Diagnostic {
+            location: location.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    fn module(&mut self, name: &str, code: &Code) {
+        let globals = code
+            .iter()
+            .filter(|i| matches!(i, Instruction::Global { .. }))
+            .count();
+        let constants = code
+            .iter()
+            .map(|i| match i {
+                Instruction::Constants { values } => values.len(),
+                _ => 0,
+            })
+            .sum();
+
+        for instruction in code.iter() {
+            match instruction {
+                Instruction::Fn {
+                    name: function,
+                    signature,
+                    code,
+                } => {
+                    // Functions without a signature start with the arguments of their callers,
+                    // their frame is unknown when the host is the only caller
+                    let locals = match signature {
+                        Some(signature) => Some(signature.frame_size() as u32),
+                        None => self
+                            .arguments
+                            .get(&(name.to_string(), function.clone()))
+                            .copied()
+                            .flatten(),
+                    };
+
+                    let mut frame = Frame {
+                        location: Location::new(name, function),
+                        locals,
+                        results: signature.as_ref().map(|s| s.results),
+                        loops: Vec::new(),
+                        globals,
+                        constants,
+                    };
+
+                    let state = self.block(code, State::new(), &mut frame);
+                    self.check_results(&state, &mut frame);
+                }
+                Instruction::Global { name: global, init } => {
+                    let mut frame = Frame {
+                        location: Location::new(name, global),
+                        locals: Some(0),
+                        results: None,
+                        loops: Vec::new(),
+                        globals,
+                        constants,
+                    };
+
+                    self.block(init, State::new(), &mut frame);
+                }
+                Instruction::Struct { .. }
+                | Instruction::Constants { .. }
+                | Instruction::Import { .. } => {}
+                _ => self.report(
+                    name,
+                    "only (fn), (struct), (global) and (import) are allowed in (mod)",
+                ),
+            }
+        }
+    }
+
+    fn error(&mut self, frame: &Frame, message: String) {
+        self.diagnostics.push(frame.location.diagnostic(message));
+    }
+
+    fn error_at(&mut self, frame: &Frame, index: usize, message: String) {
+        self.diagnostics
+            .push(frame.location.diagnostic_at(index, message));
+    }
+
+    fn check_results(&mut self, state: &State, frame: &mut Frame) {
+        if let (true, Some(depth), Some(results)) = (state.reachable, state.depth, frame.results) {
+            if depth != results {
+                self.error(
+                    frame,
+                    format!("returns {} values, declared {}", depth, results),
+                );
+            }
+        }
+    }
+
+    fn check_local(&mut self, index: u32, frame: &Frame) {
+        if let Some(locals) = frame.locals {
+            if index >= locals {
+                self.error(
+                    frame,
+                    format!("local {} out of bounds, frame has {}", index, locals),
+                );
+            }
+        }
+    }
+
+    // Apply the stack effect of an instruction that pops and pushes a fixed number of values
+    fn effect(&mut self, state: &mut State, pops: u32, pushes: Option<u32>, frame: &Frame) {
+        if let Some(depth) = state.depth {
+            if depth < pops {
+                self.error(
+                    frame,
+                    format!("stack underflow, needs {} values, found {}", pops, depth),
+                );
+                // Keep going as if the missing values were there
+                state.depth = pushes;
+                return;
+            }
+        }
+
+        state.depth = match (state.depth, pushes) {
+            (Some(depth), Some(pushes)) => (depth - pops).checked_add(pushes),
+            _ => None,
+        };
+    }
+
+    // Operands of untrusted code can be too large to count values with, the stack depth is
+    // unknown after them
+    fn out_of_range(&mut self, state: &mut State, operand: u32, frame: &Frame) {
+        self.error(frame, format!("operand {} out of range", operand));
+        state.depth = None;
+    }
+
+    // Verify a block and return the state at its end
+    fn block(&mut self, code: &Code, mut state: State, frame: &mut Frame) -> State {
+        for instruction in code.iter() {
+            if !state.reachable {
+                break;
+            }
+
+            frame.location.index += 1;
+
+            match instruction {
+                Instruction::None | Instruction::Dump | Instruction::Hi => {}
+                Instruction::Version { .. }
+                | Instruction::Fn { .. }
+                | Instruction::Module { .. }
+                | Instruction::LoadModule { .. }
+                | Instruction::GetFunction { .. }
+                | Instruction::Struct { .. }
+                | Instruction::Global { .. }
+                | Instruction::Constants { .. }
+                | Instruction::Import { .. } => {
+                    self.error(frame, "declaration not allowed in code".to_string())
+                }
+                Instruction::Call {
+                    module,
+                    function,
+                    param_count,
+                }
+                | Instruction::TailCall {
+                    module,
+                    function,
+                    param_count,
+                } => {
+                    let signature = self
+                        .signatures
+                        .get(&(module.clone(), function.clone()))
+                        .map(|s| (s.params, s.results));
+
+                    if let Some((params, _)) = signature {
+                        if params != *param_count {
+                            self.error(
+                                frame,
+                                format!(
+                                    "{}.{} expects {} arguments, called with {}",
+                                    module, function, params, param_count
+                                ),
+                            );
+                        }
+                    }
+
+                    self.effect(
+                        &mut state,
+                        *param_count,
+                        signature.map(|(_, results)| results),
+                        frame,
+                    );
+
+                    if let Instruction::TailCall { .. } = instruction {
+                        self.check_results(&state, frame);
+                        state.reachable = false;
+                    }
+                }
+                Instruction::MakeClosure { captures, .. } => {
+                    for capture in captures.iter() {
+                        let (Capture::Value(index) | Capture::Cell(index)) = capture;
+                        self.check_local(*index, frame);
+                    }
+
+                    self.effect(&mut state, 0, Some(1), frame);
+                }
+                Instruction::GetUpvalue { .. } => self.effect(&mut state, 0, Some(1), frame),
+                Instruction::SetUpvalue { .. } => self.effect(&mut state, 1, Some(0), frame),
+                Instruction::CallClosure { param_count } => match param_count.checked_add(1) {
+                    Some(pops) => self.effect(&mut state, pops, None, frame),
+                    None => self.out_of_range(&mut state, *param_count, frame),
+                },
+                Instruction::GetGlobal { index } | Instruction::SetGlobal { index } => {
+                    if *index as usize >= frame.globals {
+                        self.error(
+                            frame,
+                            format!(
+                                "global {} out of bounds, module has {}",
+                                index, frame.globals
+                            ),
+                        );
+                    }
+
+                    match instruction {
+                        Instruction::GetGlobal { .. } => self.effect(&mut state, 0, Some(1), frame),
+                        _ => self.effect(&mut state, 1, Some(0), frame),
+                    }
+                }
+                Instruction::GetConst { index } => {
+                    if *index as usize >= frame.constants {
+                        self.error(
+                            frame,
+                            format!(
+                                "constant {} out of bounds, pool has {}",
+                                index, frame.constants
+                            ),
+                        );
+                    }
+
+                    self.effect(&mut state, 0, Some(1), frame);
+                }
+                Instruction::PushConstString { .. }
+                | Instruction::PushConstInteger { .. }
+                | Instruction::PushConstFloat { .. }
+                | Instruction::PushConstBoolean { .. }
+                | Instruction::Allocate { .. }
+                | Instruction::New { .. } => self.effect(&mut state, 0, Some(1), frame),
+                Instruction::GetLocal { index } => {
+                    self.check_local(*index, frame);
+                    self.effect(&mut state, 0, Some(1), frame);
+                }
+                Instruction::SetLocal { index } => {
+                    self.check_local(*index, frame);
+                    self.effect(&mut state, 1, Some(0), frame);
+                }
+                Instruction::ReserveLocal { size } => {
+                    frame.locals = frame.locals.map(|locals| locals.max(*size));
+                }
+                Instruction::IsInstance { .. }
+                | Instruction::GetField { .. }
+                | Instruction::GetFieldByName { .. }
+                | Instruction::Inc
+                | Instruction::Dec => self.effect(&mut state, 1, Some(1), frame),
+                Instruction::SetField { .. } | Instruction::SetFieldByName { .. } => {
+                    self.effect(&mut state, 2, Some(1), frame)
+                }
+                Instruction::CallMethod { param_count, .. } => match param_count.checked_add(1) {
+                    Some(pops) => self.effect(&mut state, pops, None, frame),
+                    None => self.out_of_range(&mut state, *param_count, frame),
+                },
+                Instruction::Pop => self.effect(&mut state, 1, Some(0), frame),
+                Instruction::Dup => self.effect(&mut state, 1, Some(2), frame),
+                Instruction::Swap => self.effect(&mut state, 2, Some(2), frame),
+                Instruction::Over => self.effect(&mut state, 2, Some(3), frame),
+                Instruction::Rot => self.effect(&mut state, 3, Some(3), frame),
+                Instruction::Pick { depth } => match (depth.checked_add(1), depth.checked_add(2)) {
+                    (Some(pops), Some(pushes)) => {
+                        self.effect(&mut state, pops, Some(pushes), frame)
+                    }
+                    _ => self.out_of_range(&mut state, *depth, frame),
+                },
+                Instruction::Drop { count } => self.effect(&mut state, *count, Some(0), frame),
+                Instruction::DupN { count } => match count.checked_mul(2) {
+                    Some(pushes) => self.effect(&mut state, *count, Some(pushes), frame),
+                    None => self.out_of_range(&mut state, *count, frame),
+                },
+                Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::Eq
+                | Instruction::Ne
+                | Instruction::Lt
+                | Instruction::Le
+                | Instruction::Gt
+                | Instruction::Ge => self.effect(&mut state, 2, Some(1), frame),
+                Instruction::Return => {
+                    self.check_results(&state, frame);
+                    state.reachable = false;
+                }
+                Instruction::Then {
+                    then_block,
+                    else_block,
+                } => {
+                    self.effect(&mut state, 1, Some(0), frame);
+
+                    let index = frame.location.index;
+                    let then_state = self.block(then_block, state, frame);
+                    let else_state = self.block(else_block, state, frame);
+                    state = self.merge(&[then_state, else_state], index, frame);
+                }
+                Instruction::Switch { cases, default } => {
+                    self.effect(&mut state, 1, Some(0), frame);
+
+                    let index = frame.location.index;
+                    let mut states: Vec<State> = cases
+                        .iter()
+                        .map(|case| self.block(case, state, frame))
+                        .collect();
+                    states.push(self.block(default, state, frame));
+                    state = self.merge(&states, index, frame);
+                }
+                Instruction::Loop { block } => {
+                    let index = frame.location.index;
+                    frame.loops.push(state.depth);
+                    let end = self.block(block, state, frame);
+                    frame.loops.pop();
+
+                    if let (true, Some(start), Some(end)) = (end.reachable, state.depth, end.depth)
+                    {
+                        if start != end {
+                            self.error_at(
+                                frame,
+                                index,
+                                format!(
+                                    "loop body changes the stack depth from {} to {}",
+                                    start, end
+                                ),
+                            );
+                        }
+                    }
+                }
+                Instruction::Break { depth } | Instruction::Continue { depth } => {
+                    let target = depth
+                        .checked_add(1)
+                        .and_then(|depth| (frame.loops.len() as u32).checked_sub(depth))
+                        .map(|index| frame.loops[index as usize]);
+
+                    match target {
+                        None => {
+                            self.error(frame, "break or continue outside of a loop".to_string())
+                        }
+                        Some(Some(start)) if state.depth.is_some_and(|depth| depth != start) => {
+                            self.error(
+                                frame,
+                                format!(
+                                    "leaves the loop with stack depth {}, entered with {}",
+                                    state.depth.unwrap(),
+                                    start
+                                ),
+                            )
+                        }
+                        _ => {}
+                    }
+
+                    state.reachable = false;
+                }
+            }
+        }
+
+        state
+    }
+
+    // State after branches, every branch that falls through must leave the same depth
+    fn merge(&mut self, states: &[State], index: usize, frame: &Frame) -> State {
+        let reachable: Vec<&State> = states.iter().filter(|s| s.reachable).collect();
+
+        let Some(first) = reachable.first() else {
+            return State {
+                depth: None,
+                reachable: false,
+            };
+        };
+
+        let mut depth = first.depth;
+
+        for state in reachable.iter().skip(1) {
+            match (depth, state.depth) {
+                (Some(a), Some(b)) if a != b => {
+                    self.error_at(
+                        frame,
+                        index,
+                        format!("branches leave different stack depths {} and {}", a, b),
+                    );
+                    depth = None;
+                }
+                (Some(_), None) => depth = None,
+                _ => {}
+            }
+        }
+
+        State {
+            depth,
+            reachable: true,
+        }
+    }
+}
+
+// Arguments passed by every call and closure of a block, including nested blocks
+fn visit_calls(code: &Code, callback: &mut impl FnMut(&str, &str, Option<u32>)) {
+    for instruction in code.iter() {
+        match instruction {
+            Instruction::Call {
+                module,
+                function,
+                param_count,
+            }
+            | Instruction::TailCall {
+                module,
+                function,
+                param_count,
+            } => callback(module, function, Some(*param_count)),
+            Instruction::MakeClosure {
+                module, function, ..
+            } => callback(module, function, None),
+            Instruction::Fn { code, .. }
+            | Instruction::Global { init: code, .. }
+            | Instruction::Loop { block: code } => visit_calls(code, callback),
+            Instruction::Then {
+                then_block,
+                else_block,
+            } => {
+                visit_calls(then_block, callback);
+                visit_calls(else_block, callback);
+            }
+            Instruction::Switch { cases, default } => {
+                for case in cases.iter() {
+                    visit_calls(case, callback);
+                }
+
+                visit_calls(default, callback);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            depth: Some(0),
+            reachable: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, Loader};
+
+    fn messages(source: &str) -> Vec<String> {
+        verify(&assemble(source).unwrap())
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn verify_accepts_well_formed_code() {
+        let diagnostics = messages(
+            r#"
+            (mod main
+                (global count (i32.const 0))
+                (fn add (params 2) (results 1) (local.get 0) (local.get 1) (op.add))
+                (fn run (results 1)
+                    (loop
+                        (global.get count) (i32.const 10) (cmp.lt)
+                        (then (break))
+                        (global.get count) (op.inc) (global.set count))
+                    (i32.const 1) (i32.const 2) (call main add 2)))
+            "#,
+        );
+
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn verify_reports_every_problem() {
+        let diagnostics = messages(
+            r#"
+            (mod main
+                (fn add (params 2) (results 1) (local.get 0) (local.get 2) (op.add))
+                (fn run
+                    (i32.const 1) (call main add 1)
+                    (op.add)
+                    (bool.const true) (then (i32.const 1) else)))
+            "#,
+        );
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                "main.add@2: local 2 out of bounds, frame has 2",
+                "main.run@2: main.add expects 2 arguments, called with 1",
+                "main.run@3: stack underflow, needs 2 values, found 1",
+                "main.run@5: branches leave different stack depths 2 and 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn verify_bounds_locals_of_functions_without_signature() {
+        let diagnostics = messages(
+            r#"
+            (mod main
+                (fn first (local.get 0) (local.get 1) (op.add))
+                (fn entry (local.get 5))
+                (fn run (i32.const 1) (i32.const 2) (call main first 2) (pop)
+                    (i32.const 1) (call main first 1)))
+            "#,
+        );
+
+        assert_eq!(
+            diagnostics,
+            vec!["main.first@2: local 1 out of bounds, frame has 1"]
+        );
+    }
+
+    #[test]
+    fn loader_rejects_code_that_fails_verification() {
+        let code = assemble("(mod main (fn run (results 1) (op.add)))").unwrap();
+        let Err(error) = Loader::new().verify(true).load(&code) else {
+            unreachable!("verification should fail");
+        };
+
+        assert_eq!(
+            error,
+            "Verification failed:\n  \
+            main.run@1: stack underflow, needs 2 values, found 0"
+        );
+        assert!(Loader::new().load(&code).is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_operands_out_of_range() {
+        let instructions = [
+            Instruction::Pick { depth: u32::MAX },
+            Instruction::DupN { count: u32::MAX },
+            Instruction::CallClosure {
+                param_count: u32::MAX,
+            },
+            Instruction::CallMethod {
+                name: "m".to_string(),
+                param_count: u32::MAX,
+            },
+            Instruction::Break { depth: u32::MAX },
+            Instruction::Continue { depth: u32::MAX },
+            // The assembler rejects loop depths without a loop, decoded code can still use them
+            Instruction::Break { depth: 0 },
+        ];
+
+        let diagnostics: Vec<String> = instructions
+            .into_iter()
+            .flat_map(|instruction| {
+                let mut code = assemble("(mod main (fn run))").unwrap();
+                let Instruction::Module { code: module, .. } = &mut code[1] else {
+                    unreachable!("expected (mod)");
+                };
+                let Instruction::Fn { code: function, .. } = &mut module[0] else {
+                    unreachable!("expected (fn)");
+                };
+                function.push(instruction);
+
+                verify(&code)
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                "main.run@1: operand 4294967295 out of range",
+                "main.run@1: operand 4294967295 out of range",
+                "main.run@1: operand 4294967295 out of range",
+                "main.run@1: operand 4294967295 out of range",
+                "main.run@1: break or continue outside of a loop",
+                "main.run@1: break or continue outside of a loop",
+                "main.run@1: break or continue outside of a loop",
+            ]
+        );
+    }
+}
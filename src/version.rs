@@ -0,0 +1,252 @@
+use std::{collections::BTreeSet, fmt};
+
+use crate::{read_code, ByteCode, Code, Container, Instruction, SectionKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Version {
+    // Version of this runtime, written into the bytecode it produces
+    pub fn current() -> Version {
+        Version {
+            major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
+            minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
+            patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap(),
+        }
+    }
+
+    // Version declared by the first instruction of a program
+    pub fn of(code: &Code) -> Result<Version, String> {
+        match code.first() {
+            Some(Instruction::Version {
+                major,
+                minor,
+                patch,
+            }) => Ok(Version {
+                major: *major,
+                minor: *minor,
+                patch: *patch,
+            }),
+            Some(_) => Err("Invalid version, expected (version) first".to_string()),
+            None => Err("Missing version".to_string()),
+        }
+    }
+
+    // Semver rules: bytecode from an older release of the same major version runs, bytecode
+    // from a newer release may use features this runtime doesn't have. Before 1.0 every
+    // minor release can break the encoding.
+    pub fn check_compatible(&self, runtime: &Version) -> Result<(), String> {
+        let same_series = if runtime.major == 0 {
+            self.major == 0 && self.minor == runtime.minor
+        } else {
+            self.major == runtime.major
+        };
+
+        if !same_series {
+            return Err(format!(
+                "Bytecode version {} is incompatible with runtime version {}",
+                self, runtime
+            ));
+        }
+
+        if self > runtime {
+            return Err(format!(
+                "Bytecode version {} is newer than runtime version {}",
+                self, runtime
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Opcodes a program uses, stored in containers so older runtimes can refuse it up front
+pub fn required_opcodes(code: &Code) -> Vec<u8> {
+    let mut opcodes = BTreeSet::new();
+    collect_opcodes(code, &mut opcodes);
+    opcodes.into_iter().collect()
+}
+
+fn collect_opcodes(code: &Code, opcodes: &mut BTreeSet<u8>) {
+    for instruction in code.iter() {
+        opcodes.insert(instruction.opcode() as u8);
+
+        match instruction {
+            Instruction::Fn { code, .. }
+            | Instruction::Module { code, .. }
+            | Instruction::LoadModule { code, .. }
+            | Instruction::Global { init: code, .. }
+            | Instruction::Loop { block: code } => collect_opcodes(code, opcodes),
+            Instruction::Then {
+                then_block,
+                else_block,
+            } => {
+                if !else_block.is_empty() {
+                    opcodes.insert(ByteCode::Else as u8);
+                }
+
+                collect_opcodes(then_block, opcodes);
+                collect_opcodes(else_block, opcodes);
+            }
+            Instruction::Switch { cases, default } => {
+                for case in cases.iter() {
+                    collect_opcodes(case, opcodes);
+                }
+
+                collect_opcodes(default, opcodes);
+            }
+            Instruction::GetFunction { alias: Some(_), .. } => {
+                opcodes.insert(ByteCode::Alias as u8);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Refuse opcodes this runtime doesn't know
+pub fn check_opcodes(opcodes: &[u8]) -> Result<(), String> {
+    let unknown: Vec<String> = opcodes
+        .iter()
+        .filter(|opcode| ByteCode::from_u8(**opcode).is_none())
+        .map(|opcode| format!("0x{:02X}", opcode))
+        .collect();
+
+    if !unknown.is_empty() {
+        return Err(format!(
+            "Bytecode requires opcodes not supported by this runtime: {}",
+            unknown.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+// Rewrite bytecode of an older compatible release, bare or in a container, into a container
+// declaring the current version. Only the version header changes, instructions are written
+// back as decoded in the encoding of the source
+pub fn upgrade(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut code = read_code(bytes)?;
+    let version = Version::of(&code)?;
+    let current = Version::current();
+
+    version.check_compatible(&current)?;
+
+    // No compatible release changed the meaning of an instruction so far, a release that does
+    // has to rewrite the affected instructions here
+
+    code[0] = Instruction::Version {
+        major: current.major,
+        minor: current.minor,
+        patch: current.patch,
+    };
+
+    // Keep the other sections of containers, only the code is rewritten
+    let mut container = if Container::is_container(bytes) {
+        Container::from_bytes(bytes)?
+    } else {
+        Container::new()
+    };
+
+    container.sections.retain(|section| {
+        section.kind != SectionKind::Code && section.kind != SectionKind::Capabilities
+    });
+
+    // Bare bytecode always uses the fixed encoding
+    let upgraded = Container::from_code_with(&code, container.encoding());
+    container.sections.splice(0..0, upgraded.sections);
+
+    Ok(container.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, Encoding};
+
+    fn version(major: u8, minor: u8, patch: u8) -> Version {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    #[test]
+    fn version_compatibility() {
+        let runtime = version(1, 4, 2);
+
+        assert!(version(1, 0, 0).check_compatible(&runtime).is_ok());
+        assert!(version(1, 4, 2).check_compatible(&runtime).is_ok());
+        assert!(version(1, 4, 3).check_compatible(&runtime).is_err());
+        assert!(version(1, 5, 0).check_compatible(&runtime).is_err());
+        assert!(version(2, 0, 0).check_compatible(&runtime).is_err());
+
+        let runtime = version(0, 3, 2);
+
+        assert!(version(0, 3, 0).check_compatible(&runtime).is_ok());
+        assert!(version(0, 2, 9).check_compatible(&runtime).is_err());
+    }
+
+    #[test]
+    fn upgrade_keeps_the_compact_encoding() {
+        let mut code = assemble("(mod main (fn run (i32.const 300)))").unwrap();
+        code[0] = Instruction::Version {
+            major: 0,
+            minor: 1,
+            patch: 0,
+        };
+
+        let compact = Container::from_code_with(&code, Encoding::Compact).to_bytes();
+        let container = Container::from_bytes(&upgrade(&compact).unwrap()).unwrap();
+
+        assert_eq!(container.encoding(), Encoding::Compact);
+        assert_eq!(
+            container.get_section(SectionKind::Code).unwrap().data,
+            Instruction::code_to_bytes_with(&container.code().unwrap(), Encoding::Compact)
+        );
+        assert_eq!(
+            Version::of(&container.code().unwrap()),
+            Ok(Version::current())
+        );
+    }
+
+    #[test]
+    fn upgrade_rewrites_version_and_lists_opcodes() {
+        let mut code = assemble("(mod main (fn run (i32.const 1) (loop (break))))").unwrap();
+        code[0] = Instruction::Version {
+            major: 0,
+            minor: 1,
+            patch: 0,
+        };
+
+        let bytes = upgrade(&Instruction::code_to_bytes(&code)).unwrap();
+        let container = Container::from_bytes(&bytes).unwrap();
+
+        assert_eq!(
+            Version::of(&container.code().unwrap()),
+            Ok(Version::current())
+        );
+        assert_eq!(
+            container
+                .get_section(SectionKind::Capabilities)
+                .unwrap()
+                .data,
+            vec![0x03, 0x17, 0x1B, 0x41, 0xFA, 0xFB]
+        );
+        assert!(check_opcodes(&[0x03, 0xEE]).is_err());
+
+        for instruction in code.iter() {
+            assert_eq!(instruction.opcode() as u8, instruction.to_bytes()[0]);
+        }
+    }
+}
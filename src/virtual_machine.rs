@@ -7,7 +7,7 @@ use std::{
 use crate::{
     instruction::{Code, Instruction},
     module::Module,
-    DyModule, Function, Object, Value,
+    Capture, Closure, DyModule, Function, NativeModule, Object, Policy, Type, Value,
 };
 
 pub struct VirtualMachine {
@@ -15,9 +15,23 @@ pub struct VirtualMachine {
     pub modules: HashMap<String, Module>,
     pub dymodules: HashMap<String, DyModule>,
     pub local_vars: Vec<Vec<Value>>,
+    pub upvalues: Vec<Vec<Arc<Mutex<Value>>>>,
+    cells: Vec<HashMap<u32, Arc<Mutex<Value>>>>, // Locals of each frame captured by reference
+    pub module_stack: Vec<String>,               // Module of each function in the call stack
+    pub verified_frames: Vec<bool>, // Whether each function in the call stack was type checked
     pub call_break: bool,
     pub call_continue: bool,
     pub call_return: bool,
+    pub jump_depth: u32, // Enclosing loops a break or continue still has to leave
+    pub tail_call: Option<(String, String, Vec<Value>)>, // Call replacing the returning frame
+    pub policy: Policy,  // Host modules scripts are allowed to call
+    policies: Vec<Policy>, // Policies of the calls made with call_with_policy, innermost last
+}
+
+impl Default for VirtualMachine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> VirtualMachine {
@@ -27,20 +41,113 @@ impl<'a> VirtualMachine {
             modules: HashMap::new(),
             dymodules: HashMap::new(),
             local_vars: Vec::new(),
+            upvalues: Vec::new(),
+            cells: Vec::new(),
+            module_stack: Vec::new(),
+            verified_frames: Vec::new(),
             call_break: false,
             call_continue: false,
             call_return: false,
+            jump_depth: 0,
+            tail_call: None,
+            policy: Policy::allow_all(),
+            policies: Vec::new(),
+        }
+    }
+
+    // Add a module after checking its imports and call targets against the modules already
+    // added, modules that depend on each other have to be added together with add_modules
+    pub fn add_module(&mut self, module: Module) -> Result<(), String> {
+        self.add_modules(vec![module])
+    }
+
+    // Add a module whose symbols were already resolved
+    pub(crate) fn insert_module(&mut self, module: Module) {
+        let name = module.name.clone();
+        self.modules.insert(name.clone(), module);
+        self.init_globals(&name);
+    }
+
+    // Evaluate the init code of every global of a module in declaration order
+    fn init_globals(&mut self, module: &str) {
+        for index in 0..self.modules[module].globals.len() {
+            self.init_global(module, index);
+        }
+    }
+
+    pub(crate) fn init_global(&mut self, module: &str, index: usize) {
+        let init = self.modules[module].globals[index].init.clone();
+        let depth = self.stack.len();
+
+        self.module_stack.push(module.to_string());
+        self.verified_frames.push(false);
+        self.local_vars.push(Vec::new());
+        self.cells.push(HashMap::new());
+        self.upvalues.push(Vec::new());
+        self.execute(&init);
+        self.upvalues.pop();
+        self.cells.pop();
+        self.local_vars.pop();
+        self.verified_frames.pop();
+        self.module_stack.pop();
+
+        if let Some((module, function, args)) = self.tail_call.take() {
+            self.call(&module, &function, args);
+        }
+
+        let value = if self.stack.len() > depth {
+            self.stack.pop().unwrap()
+        } else {
+            Value::Null
+        };
+
+        self.stack.truncate(depth);
+
+        if let Some(module) = self.modules.get_mut(module) {
+            module.globals[index].value = value;
         }
     }
 
-    pub fn add_module(&mut self, module: Module) {
-        self.modules.insert(module.name.clone(), module);
+    pub fn get_global(&self, module: &str, name: &str) -> Option<&Value> {
+        self.modules
+            .get(module)
+            .and_then(|module| module.get_global(name))
+            .map(|global| &global.value)
+    }
+
+    pub fn set_global(&mut self, module: &str, name: &str, value: Value) -> Result<(), String> {
+        let Some(module) = self.modules.get_mut(module) else {
+            return Err(format!("Module \"{}\" not found", module));
+        };
+
+        let Some(global) = module.get_global_mut(name) else {
+            return Err(format!("Global \"{}\" not found", name));
+        };
+
+        global.value = value;
+        Ok(())
     }
 
     pub fn add_dynamic_module(&mut self, module: DyModule) {
         self.dymodules.insert(module.name.clone(), module);
     }
 
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    // Run a call under its own policy instead of the one of the virtual machine, so scripts
+    // of different trust levels can share the same modules
+    pub fn call_with_policy(&mut self, module: &str, name: &str, args: Vec<Value>, policy: Policy) {
+        self.policies.push(policy);
+        self.call(module, name, args);
+        self.policies.pop();
+    }
+
+    pub fn add_native_module(&mut self, module: NativeModule) {
+        self.add_dynamic_module(module.into());
+    }
+
     pub fn execute(&mut self, code: &'a Code) {
         self.call_break = false;
         self.call_continue = false;
@@ -61,7 +168,11 @@ impl<'a> VirtualMachine {
                 Instruction::Hi => {
                     println!("Hi!");
                 }
-                Instruction::Fn { name: _, code: _ } => {
+                Instruction::Fn {
+                    name: _,
+                    signature: _,
+                    code: _,
+                } => {
                     panic!("Function declaration not allowed here");
                 }
                 Instruction::Call {
@@ -73,12 +184,159 @@ impl<'a> VirtualMachine {
                         .stack
                         .split_off(self.stack.len() - *param_count as usize);
 
-                    self.call(module, function, args);
+                    // Arguments passed by type checked code already have the declared types
+                    let checked = self.verified_frames.last() == Some(&true);
+                    self.call_with_upvalues(module, function, args, Vec::new(), checked);
+
+                    self.call_return = false;
+                    self.call_continue = false;
+                    self.call_break = false;
+                }
+                Instruction::TailCall {
+                    module,
+                    function,
+                    param_count,
+                } => {
+                    let args = self
+                        .stack
+                        .split_off(self.stack.len() - *param_count as usize);
+
+                    self.tail_call = Some((module.clone(), function.clone(), args));
+                    self.call_return = true;
+                    return;
+                }
+                Instruction::MakeClosure {
+                    module,
+                    function,
+                    captures,
+                } => {
+                    let (Some(locals), Some(cells)) =
+                        (self.local_vars.last(), self.cells.last_mut())
+                    else {
+                        panic!("Local variable not found");
+                    };
+
+                    let mut upvalues = Vec::new();
+
+                    for capture in captures.iter() {
+                        match capture {
+                            Capture::Value(index) => {
+                                let value = match cells.get(index) {
+                                    Some(cell) => cell.lock().unwrap().clone(),
+                                    None => locals[*index as usize].clone(),
+                                };
+
+                                upvalues.push(value.cell());
+                            }
+                            Capture::Cell(index) => {
+                                // From now on the local lives in the cell it shares with closures
+                                let cell = cells
+                                    .entry(*index)
+                                    .or_insert_with(|| locals[*index as usize].clone().cell());
+
+                                upvalues.push(cell.clone());
+                            }
+                        }
+                    }
+
+                    self.stack.push(Value::closure(Closure {
+                        module: module.clone(),
+                        function: function.clone(),
+                        upvalues,
+                    }));
+                }
+                Instruction::GetUpvalue { index } => {
+                    let Some(upvalue) = self
+                        .upvalues
+                        .last()
+                        .and_then(|upvalues| upvalues.get(*index as usize))
+                    else {
+                        panic!("Upvalue not found");
+                    };
+
+                    let value = upvalue.lock().unwrap().clone();
+                    self.stack.push(value);
+                }
+                Instruction::SetUpvalue { index } => {
+                    let Some(value) = self.stack.pop() else {
+                        panic!("No elements in the stack expected a value");
+                    };
+
+                    let Some(upvalue) = self
+                        .upvalues
+                        .last()
+                        .and_then(|upvalues| upvalues.get(*index as usize))
+                    else {
+                        panic!("Upvalue not found");
+                    };
+
+                    *upvalue.lock().unwrap() = value;
+                }
+                Instruction::CallClosure { param_count } => {
+                    let Some(closure) = self.stack.pop() else {
+                        panic!("No elements in the stack expected a closure");
+                    };
+
+                    let Value::Closure(closure) = closure else {
+                        panic!("Expected a closure");
+                    };
+
+                    let args = self
+                        .stack
+                        .split_off(self.stack.len() - *param_count as usize);
+
+                    self.call_closure(&closure, args);
 
                     self.call_return = false;
                     self.call_continue = false;
                     self.call_break = false;
                 }
+                Instruction::Global { name: _, init: _ } => {
+                    panic!("Global declaration not allowed here");
+                }
+                Instruction::GetGlobal { index } => {
+                    let Some(global) = self
+                        .module_stack
+                        .last()
+                        .and_then(|module| self.modules.get(module))
+                        .and_then(|module| module.globals.get(*index as usize))
+                    else {
+                        panic!("Global not found");
+                    };
+
+                    self.stack.push(global.value.clone());
+                }
+                Instruction::SetGlobal { index } => {
+                    let Some(value) = self.stack.pop() else {
+                        panic!("No elements in the stack expected a value");
+                    };
+
+                    let Some(global) = self
+                        .module_stack
+                        .last()
+                        .and_then(|module| self.modules.get_mut(module))
+                        .and_then(|module| module.globals.get_mut(*index as usize))
+                    else {
+                        panic!("Global not found");
+                    };
+
+                    global.value = value;
+                }
+                Instruction::Constants { values: _ } => {
+                    panic!("Constant pool not allowed here");
+                }
+                Instruction::GetConst { index } => {
+                    let Some(constant) = self
+                        .module_stack
+                        .last()
+                        .and_then(|module| self.modules.get(module))
+                        .and_then(|module| module.constants.get(*index as usize))
+                    else {
+                        panic!("Constant not found");
+                    };
+
+                    self.stack.push(Value::from(constant));
+                }
                 Instruction::PushConstString { value } => {
                     self.stack.push(Value::String(value.clone()));
                 }
@@ -93,7 +351,10 @@ impl<'a> VirtualMachine {
                 }
                 Instruction::GetLocal { index } => {
                     if let Some(locals) = self.local_vars.last() {
-                        self.stack.push(locals[*index as usize].clone());
+                        match self.cells.last().and_then(|cells| cells.get(index)) {
+                            Some(cell) => self.stack.push(cell.lock().unwrap().clone()),
+                            None => self.stack.push(locals[*index as usize].clone()),
+                        }
                     } else {
                         panic!("Local variable not found");
                     }
@@ -101,7 +362,10 @@ impl<'a> VirtualMachine {
                 Instruction::SetLocal { index } => {
                     if let Some(value) = self.stack.pop() {
                         if let Some(locals) = self.local_vars.last_mut() {
-                            locals[*index as usize] = value;
+                            match self.cells.last().and_then(|cells| cells.get(index)) {
+                                Some(cell) => *cell.lock().unwrap() = value,
+                                None => locals[*index as usize] = value,
+                            }
                         } else {
                             panic!("Local variable not found");
                         }
@@ -111,15 +375,48 @@ impl<'a> VirtualMachine {
                 }
                 Instruction::ReserveLocal { size } => {
                     if let Some(locals) = self.local_vars.last_mut() {
-                        locals.resize(*size as usize, Value::Null);
+                        // Only grow the frame, arguments already stored must survive
+                        if locals.len() < *size as usize {
+                            locals.resize(*size as usize, Value::Null);
+                        }
                     } else {
                         panic!("Local variable not found");
                     }
                 }
                 Instruction::Allocate { fields } => {
                     let fields = vec![Value::Null; *fields as usize];
-                    self.stack
-                        .push(Value::Object(Arc::new(Mutex::new(Object::Values(fields)))));
+                    self.stack.push(Value::object(Object::Values(fields)));
+                }
+                Instruction::Struct { name: _, fields: _ } => {
+                    panic!("Struct declaration not allowed here");
+                }
+                Instruction::Import {
+                    module: _,
+                    functions: _,
+                } => {
+                    panic!("Import declaration not allowed here");
+                }
+                Instruction::New { name, fields } => {
+                    let fields = vec![Value::Null; *fields as usize];
+                    self.stack.push(Value::object(Object::Struct {
+                        name: name.clone(),
+                        fields,
+                    }));
+                }
+                Instruction::IsInstance { name } => {
+                    let Some(value) = self.stack.pop() else {
+                        panic!("No elements in the stack");
+                    };
+
+                    let result = match value {
+                        Value::Object(object) => matches!(
+                            &*object.lock().unwrap(),
+                            Object::Struct { name: type_name, .. } if type_name == name
+                        ),
+                        _ => false,
+                    };
+
+                    self.stack.push(Value::Boolean(result));
                 }
                 Instruction::GetField { index } => {
                     let Some(object) = self.stack.pop() else {
@@ -130,14 +427,21 @@ impl<'a> VirtualMachine {
                         let lock = object.lock();
                         let object = lock.as_deref().unwrap();
 
-                        if let Object::Values(fields) = object {
-                            if let Some(value) = fields.get(*index as usize) {
-                                self.stack.push(value.clone());
-                            } else {
-                                panic!("Field not found");
+                        match object {
+                            Object::Values(fields) | Object::Struct { fields, .. } => {
+                                if let Some(value) = fields.get(*index as usize) {
+                                    self.stack.push(value.clone());
+                                } else {
+                                    panic!("Field not found");
+                                }
+                            }
+                            Object::Native(native) => {
+                                if let Some(value) = native.get_field(*index) {
+                                    self.stack.push(value);
+                                } else {
+                                    panic!("Field not found");
+                                }
                             }
-                        } else {
-                            panic!("Expected an object with fields");
                         }
                     } else {
                         panic!("Expected an object");
@@ -156,21 +460,99 @@ impl<'a> VirtualMachine {
                         let object = lock.as_deref_mut().unwrap();
 
                         match object {
-                            Object::Values(fields) => {
-                                if let Some(_) = fields.get(*index as usize) {
+                            Object::Values(fields) | Object::Struct { fields, .. } => {
+                                if fields.get(*index as usize).is_some() {
                                     fields[*index as usize] = value;
                                 } else {
                                     panic!("Field not found");
                                 }
                             }
-                            _ => {
-                                panic!("Expected an object with fields");
+                            Object::Native(native) => {
+                                if let Err(error) = native.set_field(*index, value) {
+                                    panic!("{}", error);
+                                }
                             }
                         }
                     } else {
                         panic!("Expected an object");
                     }
                 }
+                Instruction::GetFieldByName { name } => {
+                    let Some(object) = self.stack.pop() else {
+                        panic!("No elements in the stack expected an object");
+                    };
+
+                    let Value::Object(object) = object else {
+                        panic!("Expected an object");
+                    };
+
+                    let value = match &*object.lock().unwrap() {
+                        Object::Native(native) => native.get_field_by_name(name),
+                        Object::Struct {
+                            name: struct_name,
+                            fields,
+                        } => self
+                            .struct_field(struct_name, name)
+                            .and_then(|index| fields.get(index).cloned()),
+                        Object::Values(_) => None,
+                    };
+
+                    match value {
+                        Some(value) => self.stack.push(value),
+                        None => panic!("Field \"{}\" not found", name),
+                    }
+                }
+                Instruction::SetFieldByName { name } => {
+                    let Some(value) = self.stack.pop() else {
+                        panic!("No elements in the stack expected a value");
+                    };
+                    let Some(Value::Object(object)) = self.stack.last() else {
+                        panic!("Expected an object");
+                    };
+
+                    let result = match &mut *object.lock().unwrap() {
+                        Object::Native(native) => native.set_field_by_name(name, value),
+                        Object::Struct {
+                            name: struct_name,
+                            fields,
+                        } => match self.struct_field(struct_name, name) {
+                            Some(index) if index < fields.len() => {
+                                fields[index] = value;
+                                Ok(())
+                            }
+                            _ => Err(format!("{} has no field {}", struct_name, name)),
+                        },
+                        Object::Values(_) => Err(format!("Object has no field {}", name)),
+                    };
+
+                    if let Err(error) = result {
+                        panic!("{}", error);
+                    }
+                }
+                Instruction::CallMethod { name, param_count } => {
+                    let args = self
+                        .stack
+                        .split_off(self.stack.len() - *param_count as usize);
+
+                    let Some(object) = self.stack.pop() else {
+                        panic!("No elements in the stack expected an object");
+                    };
+
+                    let Value::Object(object) = object else {
+                        panic!("Expected an object");
+                    };
+
+                    let result = match &mut *object.lock().unwrap() {
+                        Object::Native(native) => native.call_method(name, args),
+                        object => Err(format!("{} has no methods", object.type_name())),
+                    };
+
+                    match result {
+                        Ok(Some(value)) => self.stack.push(value),
+                        Ok(None) => {}
+                        Err(error) => panic!("{}", error),
+                    }
+                }
                 Instruction::Pop => {
                     self.stack.pop();
                 }
@@ -181,6 +563,73 @@ impl<'a> VirtualMachine {
                         panic!("No elements in the stack");
                     }
                 }
+                Instruction::Swap => {
+                    let len = self.stack.len();
+
+                    if len < 2 {
+                        panic!("Stack underflow: swap expects 2 elements, found {}", len);
+                    }
+
+                    self.stack.swap(len - 1, len - 2);
+                }
+                Instruction::Over => {
+                    let len = self.stack.len();
+
+                    if len < 2 {
+                        panic!("Stack underflow: over expects 2 elements, found {}", len);
+                    }
+
+                    self.stack.push(self.stack[len - 2].clone());
+                }
+                Instruction::Rot => {
+                    let len = self.stack.len();
+
+                    if len < 3 {
+                        panic!("Stack underflow: rot expects 3 elements, found {}", len);
+                    }
+
+                    let value = self.stack.remove(len - 3);
+                    self.stack.push(value);
+                }
+                Instruction::Pick { depth } => {
+                    let len = self.stack.len();
+
+                    if len <= *depth as usize {
+                        panic!(
+                            "Stack underflow: pick {} expects {} elements, found {}",
+                            depth,
+                            depth + 1,
+                            len
+                        );
+                    }
+
+                    self.stack
+                        .push(self.stack[len - 1 - *depth as usize].clone());
+                }
+                Instruction::Drop { count } => {
+                    let len = self.stack.len();
+
+                    if len < *count as usize {
+                        panic!(
+                            "Stack underflow: drop {} expects {} elements, found {}",
+                            count, count, len
+                        );
+                    }
+
+                    self.stack.truncate(len - *count as usize);
+                }
+                Instruction::DupN { count } => {
+                    let len = self.stack.len();
+
+                    if len < *count as usize {
+                        panic!(
+                            "Stack underflow: dup {} expects {} elements, found {}",
+                            count, count, len
+                        );
+                    }
+
+                    self.stack.extend_from_within(len - *count as usize..);
+                }
                 Instruction::Add => {
                     let Some(a) = self.stack.pop() else {
                         panic!("No elements in the stack");
@@ -452,40 +901,73 @@ impl<'a> VirtualMachine {
                     };
 
                     if let Value::Boolean(value) = value {
-                        let result = if value {
+                        if value {
                             self.execute(then_block)
                         } else {
                             self.execute(else_block)
                         };
 
                         if self.call_return || self.call_break || self.call_continue {
-                            return result;
+                            return;
                         }
                     } else {
                         panic!("Invalid value");
                     }
                 }
                 Instruction::Loop { block } => loop {
-                    let result = self.execute(block);
+                    self.execute(block);
 
                     if self.call_break {
+                        if self.jump_depth > 0 {
+                            self.jump_depth -= 1;
+                            return;
+                        }
+
+                        self.call_break = false;
                         break;
                     }
 
                     if self.call_continue {
+                        if self.jump_depth > 0 {
+                            self.jump_depth -= 1;
+                            return;
+                        }
+
                         continue;
                     }
 
                     if self.call_return {
-                        return result;
+                        return;
                     }
                 },
-                Instruction::Break => {
+                Instruction::Switch { cases, default } => {
+                    let Some(value) = self.stack.pop() else {
+                        panic!("No elements in the stack");
+                    };
+
+                    let Value::Integer(value) = value else {
+                        panic!("Invalid value");
+                    };
+
+                    let block = usize::try_from(value)
+                        .ok()
+                        .and_then(|index| cases.get(index))
+                        .unwrap_or(default);
+
+                    self.execute(block);
+
+                    if self.call_return || self.call_break || self.call_continue {
+                        return;
+                    }
+                }
+                Instruction::Break { depth } => {
                     self.call_break = true;
+                    self.jump_depth = *depth;
                     return;
                 }
-                Instruction::Continue => {
+                Instruction::Continue { depth } => {
                     self.call_continue = true;
+                    self.jump_depth = *depth;
                     return;
                 }
             }
@@ -493,42 +975,139 @@ impl<'a> VirtualMachine {
     }
 
     pub fn call(&mut self, module: &str, name: &str, args: Vec<Value>) {
-        let Some(module) = self.modules.get_mut(module) else {
-            let Some(dymodule) = self.dymodules.get(module) else {
-                panic!("Module \"{}\" not found", module);
-            };
+        self.call_with_upvalues(module, name, args, Vec::new(), false);
+    }
+
+    pub fn call_closure(&mut self, closure: &Closure, args: Vec<Value>) {
+        self.call_with_upvalues(
+            &closure.module,
+            &closure.function,
+            args,
+            closure.upvalues.clone(),
+            false,
+        );
+    }
+
+    fn call_with_upvalues(
+        &mut self,
+        module: &str,
+        name: &str,
+        args: Vec<Value>,
+        upvalues: Vec<Arc<Mutex<Value>>>,
+        checked: bool,
+    ) {
+        let mut module = module.to_string();
+        let mut checked = checked;
+        let mut name = name.to_string();
+        let mut args = args;
+        let mut upvalues = upvalues;
 
-            if let Some(function) = dymodule.fns.get(name) {
-                let result = function(args);
+        // Tail calls replace the current frame instead of growing the Rust stack
+        loop {
+            let Some(script_module) = self.modules.get_mut(&module) else {
+                let Some(dymodule) = self.dymodules.get_mut(&module) else {
+                    panic!("Module \"{}\" not found", module);
+                };
 
-                if let Some(result) = result {
-                    self.stack.push(result);
+                let policy = self.policies.last().unwrap_or(&self.policy);
+
+                if let Err(error) = policy.check_call(&module, &name) {
+                    panic!("{}", error);
+                }
+
+                match dymodule.call(&name, args) {
+                    Ok(Some(result)) => self.stack.push(result),
+                    Ok(None) => {}
+                    Err(error) => panic!("{}", error),
                 }
 
                 return;
+            };
+
+            if let Some(function) = script_module.get_function_mut(&name) {
+                let code = function.code.clone();
+
+                // The frame of functions with a signature is allocated once for all locals
+                if let Some(signature) = &function.signature {
+                    if args.len() != signature.params as usize {
+                        panic!(
+                            "Function \"{}.{}\" expects {} arguments, found {}",
+                            module,
+                            name,
+                            signature.params,
+                            args.len()
+                        );
+                    }
+
+                    if !checked {
+                        for (index, (arg, expected)) in
+                            args.iter().zip(signature.param_types.iter()).enumerate()
+                        {
+                            if !expected.accepts(Type::of(arg)) {
+                                panic!(
+                                    "Function \"{}.{}\" argument {} expects {}, found {:?}",
+                                    module,
+                                    name,
+                                    index,
+                                    expected.name(),
+                                    arg
+                                );
+                            }
+                        }
+                    }
+
+                    args.extend((0..signature.locals as usize).map(|index| {
+                        signature
+                            .local_types
+                            .get(index)
+                            .map_or(Value::Null, Type::default_value)
+                    }));
+                }
+
+                let verified = function.verified;
+
+                self.module_stack.push(module);
+                self.verified_frames.push(verified);
+                self.local_vars.push(args);
+                self.cells.push(HashMap::new());
+                self.upvalues.push(upvalues);
+                self.execute(&code);
+                self.upvalues.pop();
+                self.cells.pop();
+                self.local_vars.pop();
+                self.verified_frames.pop();
+                self.module_stack.pop();
+
+                // A tail call is made by the function that just returned
+                checked = verified;
             } else {
                 panic!("Function not found");
             }
-        };
 
-        if let Some(function) = module.get_function_mut(name) {
-            self.local_vars.push(args);
-            let code = function.code.clone();
-            self.execute(&code);
-            self.local_vars.pop();
-            return;
-        } else {
-            panic!("Function not found");
+            let Some((next_module, next_name, next_args)) = self.tail_call.take() else {
+                return;
+            };
+
+            module = next_module;
+            name = next_name;
+            args = next_args;
+            upvalues = Vec::new();
         }
     }
 
+    // Index of a field of a struct declared by the module of the running function
+    fn struct_field(&self, name: &str, field: &str) -> Option<usize> {
+        let module = self.modules.get(self.module_stack.last()?)?;
+        module.structs.get(name)?.iter().position(|f| f == field)
+    }
+
     pub fn has_function(&self, module: &str, name: &str) -> bool {
         if let Some(module) = self.modules.get(module) {
-            if let Some(_) = module.get_function(name) {
+            if module.get_function(name).is_some() {
                 return true;
             }
         } else if let Some(dymodule) = self.dymodules.get(module) {
-            if let Some(_) = dymodule.fns.get(name) {
+            if dymodule.fns.contains_key(name) {
                 return true;
             }
         }
@@ -546,3 +1125,402 @@ impl<'a> VirtualMachine {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::{assemble, optimize_tail_calls},
+        load_modules, NativeObject,
+    };
+
+    fn run(source: &str, module: &str, function: &str) -> VirtualMachine {
+        let bytes = Instruction::code_to_bytes(&assemble(source).unwrap());
+        let (modules, _) = load_modules(&Instruction::from_bytecode(&bytes).unwrap()).unwrap();
+        let mut vm = VirtualMachine::new();
+
+        vm.add_modules(modules).unwrap();
+
+        vm.call(module, function, vec![]);
+        vm
+    }
+
+    #[test]
+    fn closure_captures_by_value_and_cell() {
+        let vm = run(
+            r#"
+            (mod main
+                (fn incr (upval.get 0) (op.inc) (dup) (upval.set 0))
+                (fn snapshot (upval.get 0))
+                (fn run
+                    (local.reserve 3)
+                    (i32.const 10) (local.set 0)
+                    (closure main incr (cell 0)) (local.set 1)
+                    (closure main snapshot (val 0)) (local.set 2)
+                    (local.get 1) (call.closure 0) (pop)
+                    (local.get 1) (call.closure 0)
+                    (local.get 0)
+                    (local.get 2) (call.closure 0)))
+            "#,
+            "main",
+            "run",
+        );
+
+        assert_eq!(format!("{:?}", vm.stack), "[12, 12, 10]");
+    }
+
+    #[test]
+    fn struct_fields_resolve_by_name() {
+        let vm = run(
+            r#"
+            (mod main
+                (fn run
+                    (new Point)
+                    (i32.const 3) (field.set Point.y)
+                    (dup) (is Point)
+                    (local.reserve 1) (local.set 0)
+                    (dup) (field.get Point.y)
+                    (local.get 0))
+                (struct Point x y))
+            "#,
+            "main",
+            "run",
+        );
+
+        assert_eq!(format!("{:?}", vm.stack), "[Point[Null, 3], 3, true]");
+    }
+
+    #[test]
+    fn globals_persist_between_calls() {
+        let mut vm = run(
+            r#"
+            (mod main
+                (global base (i32.const 40))
+                (global counter (global.get base) (op.inc))
+                (fn tick (global.get counter) (op.inc) (global.set counter)))
+            "#,
+            "main",
+            "tick",
+        );
+
+        vm.call("main", "tick", vec![]);
+        assert_eq!(
+            format!("{:?}", vm.get_global("main", "counter")),
+            "Some(43)"
+        );
+
+        vm.set_global("main", "counter", Value::Integer(0)).unwrap();
+        vm.call("main", "tick", vec![]);
+        assert_eq!(format!("{:?}", vm.get_global("main", "counter")), "Some(1)");
+        assert!(vm.set_global("main", "missing", Value::Null).is_err());
+    }
+
+    #[test]
+    fn stack_manipulation() {
+        let vm = run(
+            r#"
+            (mod main
+                (fn run
+                    (i32.const 1) (i32.const 2) (i32.const 3)
+                    (rot) (swap) (over) (pick 3) (dup 2) (drop 1)))
+            "#,
+            "main",
+            "run",
+        );
+
+        assert_eq!(format!("{:?}", vm.stack), "[2, 1, 3, 1, 2, 1]");
+    }
+
+    #[test]
+    #[should_panic(expected = "Stack underflow")]
+    fn stack_manipulation_underflow() {
+        run("(mod main (fn run (i32.const 1) (swap)))", "main", "run");
+    }
+
+    #[test]
+    fn switch_selects_case_or_default() {
+        let vm = run(
+            r#"
+            (mod main
+                (fn pick
+                    (local.get 0)
+                    (switch
+                        (case (str.const "zero"))
+                        (case (str.const "one"))
+                        (default (str.const "other"))))
+                (fn run
+                    (i32.const 1) (call main pick 1)
+                    (i32.const 0) (call main pick 1)
+                    (i32.const 7) (call main pick 1)
+                    (i32.const -1) (call main pick 1)))
+            "#,
+            "main",
+            "run",
+        );
+
+        assert_eq!(
+            format!("{:?}", vm.stack),
+            "[\"one\", \"zero\", \"other\", \"other\"]"
+        );
+    }
+
+    #[test]
+    fn labeled_break_and_continue() {
+        // Count the (i, j) pairs with j < i for i < 4, leaving both loops once i reaches 4
+        let vm = run(
+            r#"
+            (mod main
+                (fn run
+                    (local.reserve 3)
+                    (i32.const 0) (local.set 0)
+                    (i32.const 0) (local.set 2)
+                    (loop $outer
+                        (local.get 0) (op.inc) (local.set 0)
+                        (i32.const 0) (local.set 1)
+                        (loop $inner
+                            (i32.const 4) (local.get 0) (cmp.ge)
+                            (then (break $outer))
+                            (local.get 1) (op.inc) (local.set 1)
+                            (local.get 0) (local.get 1) (cmp.gt)
+                            (then (continue $outer))
+                            (local.get 2) (op.inc) (local.set 2)))
+                    (local.get 2)))
+            "#,
+            "main",
+            "run",
+        );
+
+        assert_eq!(format!("{:?}", vm.stack), "[6]");
+
+        assert_eq!(
+            assemble("(mod main (fn run (loop (break 1))))").err(),
+            Some("Loop depth 1 out of range, 1 enclosing loops".to_string())
+        );
+        assert_eq!(
+            assemble("(mod main (fn run (loop (continue 0)) (break 0)))").err(),
+            Some("Loop depth 0 out of range, 0 enclosing loops".to_string())
+        );
+        assert_eq!(
+            assemble("(mod main (fn run (break)))").err(),
+            Some("Loop depth 0 out of range, 0 enclosing loops".to_string())
+        );
+        assert_eq!(
+            assemble("(mod main (fn run (loop (break)) (continue)))").err(),
+            Some("Loop depth 0 out of range, 0 enclosing loops".to_string())
+        );
+    }
+
+    #[test]
+    fn tail_calls_do_not_grow_the_stack() {
+        let source = r#"
+            (mod main
+                (fn count
+                    (i32.const 0) (local.get 0) (cmp.eq)
+                    (then (local.get 1) (return))
+                    (local.get 0) (op.dec)
+                    (local.get 1) (op.inc)
+                    (call other count 2)
+                    (return))
+                (fn run (i32.const 100000) (i32.const 0) (call main count 2)))
+            (mod other
+                (fn count (local.get 0) (local.get 1) (call main count 2) (return)))
+            "#;
+
+        let mut code = assemble(source).unwrap();
+        optimize_tail_calls(&mut code);
+
+        let (modules, _) = load_modules(&code).unwrap();
+        let mut vm = VirtualMachine::new();
+
+        vm.add_modules(modules).unwrap();
+
+        vm.call("main", "run", vec![]);
+        assert_eq!(format!("{:?}", vm.stack), "[100000]");
+    }
+
+    struct Counter {
+        count: i32,
+        dropped: Arc<Mutex<bool>>,
+    }
+
+    impl NativeObject for Counter {
+        fn type_name(&self) -> &str {
+            "Counter"
+        }
+
+        fn get_field(&self, index: u32) -> Option<Value> {
+            (index == 0).then_some(Value::Integer(self.count))
+        }
+
+        fn get_field_by_name(&self, name: &str) -> Option<Value> {
+            (name == "count").then_some(Value::Integer(self.count))
+        }
+
+        fn set_field_by_name(&mut self, name: &str, value: Value) -> Result<(), String> {
+            match (name, value) {
+                ("count", Value::Integer(count)) => {
+                    self.count = count;
+                    Ok(())
+                }
+                _ => Err(format!("Counter has no field {}", name)),
+            }
+        }
+
+        fn call_method(&mut self, name: &str, args: Vec<Value>) -> Result<Option<Value>, String> {
+            match (name, args.as_slice()) {
+                ("add", [Value::Integer(n)]) => {
+                    self.count += n;
+                    Ok(None)
+                }
+                _ => Err(format!("Counter has no method {}", name)),
+            }
+        }
+
+        fn on_drop(&mut self) {
+            *self.dropped.lock().unwrap() = true;
+        }
+    }
+
+    #[test]
+    fn native_object_fields_and_methods() {
+        let (modules, _) = load_modules(
+            &assemble(
+                r#"
+                (mod main
+                    (fn run
+                        (local.get 0) (i32.const 5) (call.method add 1)
+                        (local.get 0) (field.get 0)
+                        (local.get 0) (local.get 0) (field.get count) (op.inc) (field.set count)
+                        (field.get count)))
+                (mod shapes
+                    (struct point x y)
+                    (fn run (new point) (i32.const 3) (field.set y) (field.get y)))
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let dropped = Arc::new(Mutex::new(false));
+        let counter = Value::native(Counter {
+            count: 1,
+            dropped: dropped.clone(),
+        });
+
+        let mut vm = VirtualMachine::new();
+
+        vm.add_modules(modules).unwrap();
+
+        vm.call("main", "run", vec![counter.clone()]);
+        vm.call("shapes", "run", vec![]);
+
+        assert_eq!(format!("{:?}", vm.stack), "[6, 7, 3]");
+
+        let Value::Object(object) = counter else {
+            unreachable!();
+        };
+
+        assert_eq!(
+            object
+                .lock()
+                .unwrap()
+                .downcast_ref::<Counter>()
+                .unwrap()
+                .count,
+            7
+        );
+        assert_eq!(format!("{:?}", object.lock().unwrap()), "Counter");
+
+        drop(object);
+        assert!(*dropped.lock().unwrap());
+    }
+
+    #[test]
+    fn string_literals_are_pooled_per_module() {
+        let source = r#"
+            (mod main
+                (fn run
+                    (str.const "hello") (str.const "world") (str.const "hello")
+                    (op.add) (op.add)))
+            "#;
+
+        let bytes = Instruction::code_to_bytes(&assemble(source).unwrap());
+        let count = bytes.windows(5).filter(|w| w == b"hello").count();
+        assert_eq!(count, 1);
+
+        let vm = run(source, "main", "run");
+        assert_eq!(format!("{:?}", vm.stack), "[\"helloworldhello\"]");
+    }
+
+    #[test]
+    fn const_get_interns_literals() {
+        let source = r#"
+            (mod main
+                (fn run
+                    (i32.const 70000) (i32.const 70000) (op.add)
+                    (const.get 1.5) (const.get 1.5) (const.get 0.0) (const.get -0.0)))
+            "#;
+
+        let code = assemble(source).unwrap();
+        let Instruction::Module { code, .. } = &code[1] else {
+            unreachable!("Expected (mod)");
+        };
+
+        // Numbers pushed with i32.const and f32.const stay inline
+        assert_eq!(
+            format!("{:?}", code[0]),
+            "Constants { values: [Float(1.5), Float(0.0), Float(-0.0)] }"
+        );
+
+        let vm = run(source, "main", "run");
+        assert_eq!(format!("{:?}", vm.stack), "[140000, 1.5, 1.5, 0, -0]");
+    }
+
+    #[test]
+    fn aliased_function_import_keeps_following_instructions() {
+        let code = assemble("(mod.load libmath (fn.get sin as sine) (fn.get cos))").unwrap();
+        let bytes = Instruction::code_to_bytes(&code);
+
+        let Instruction::LoadModule { code, .. } = &Instruction::from_bytecode(&bytes).unwrap()[1]
+        else {
+            unreachable!("Expected (mod.load)");
+        };
+
+        assert_eq!(
+            format!("{:?}", code),
+            "[GetFunction { name: \"sin\", alias: Some(\"sine\") }, GetFunction { name: \"cos\", alias: None }]"
+        );
+    }
+
+    #[test]
+    fn signature_allocates_frame_once() {
+        let vm = run(
+            r#"
+            (mod main
+                (fn add (params 2) (results 1) (locals i32)
+                    (local.reserve 1)
+                    (local.get 0) (local.get 1) (op.add) (local.set 2)
+                    (local.get 2))
+                (fn run (i32.const 2) (i32.const 3) (call main add 2)))
+            "#,
+            "main",
+            "run",
+        );
+
+        assert_eq!(format!("{:?}", vm.stack), "[5]");
+    }
+
+    #[test]
+    #[should_panic(expected = "Function \"main.add\" expects 2 arguments, found 1")]
+    fn signature_checks_argument_count() {
+        run(
+            r#"
+            (mod main
+                (fn add (params 2) (results 1) (local.get 0) (local.get 1) (op.add))
+                (fn run (i32.const 2) (call main add 1)))
+            "#,
+            "main",
+            "run",
+        );
+    }
+}